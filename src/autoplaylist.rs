@@ -0,0 +1,218 @@
+pub use errors::*;
+
+use config::GeneratePlaylists;
+use musicfile::Action;
+use playlist;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// A file that ended up in the destination
+struct Placed<'a> {
+    /// Where it came from
+    source: &'a Path,
+    /// Where it is now, relative to the destination folder
+    relative: &'a Path,
+    /// Where it is now
+    dest: &'a Path,
+}
+
+/// Writes the album, artist and recently added playlists into the destination.  Albums are the
+/// folders that hold music files and artists are the top-level folders of the destination, so
+/// only files that were synced appear.  Returns the playlists that were written.
+///
+/// # Arguments
+///
+/// * `results` - What happened to each music file, by source path
+/// * `dest_folder` - The folder that processed music files were put in
+/// * `settings` - Which playlists to generate, and how
+pub fn generate(
+    results: &HashMap<PathBuf, Result<Action>>,
+    dest_folder: &Path,
+    settings: &GeneratePlaylists,
+) -> Result<Vec<PathBuf>> {
+    let placed: Vec<Placed> = results
+        .iter()
+        .filter_map(|(source, result)| {
+            result.as_ref().ok().and_then(|action| action.dest()).and_then(
+                |dest| {
+                    dest.strip_prefix(dest_folder).ok().map(|relative| {
+                        Placed {
+                            source: source,
+                            relative: relative,
+                            dest: dest,
+                        }
+                    })
+                },
+            )
+        })
+        .collect();
+
+    let folder = dest_folder.join(&settings.folder);
+    let mut playlists = BTreeMap::new();
+    if settings.albums {
+        for (name, files) in group(&placed, album_name) {
+            playlists.insert(folder.join("Albums").join(name + ".m3u8"), files);
+        }
+    }
+    if settings.artists {
+        for (name, files) in group(&placed, artist_name) {
+            playlists.insert(folder.join("Artists").join(name + ".m3u8"), files);
+        }
+    }
+    if let Some(days) = settings.recently_added_days {
+        playlists.insert(
+            folder.join("Recently Added.m3u8"),
+            recently_added(&placed, Duration::from_secs(days * 24 * 60 * 60)),
+        );
+    }
+
+    for (dest, files) in &playlists {
+        playlist::write_m3u8(dest, files, &settings.path_separator, settings.encoding)
+            .chain_err(|| format!("Unable to write playlist {}", dest.display()))?;
+    }
+    Ok(playlists.into_iter().map(|(dest, _)| dest).collect())
+}
+
+/// Groups placed files into named playlists, sorted by path
+///
+/// # Arguments
+///
+/// * `placed` - The files in the destination
+/// * `name` - Gives the playlist a file belongs in, if any
+fn group<F>(placed: &[Placed], name: F) -> BTreeMap<String, Vec<PathBuf>>
+where
+    F: Fn(&Path) -> Option<String>,
+{
+    let mut groups = BTreeMap::new();
+    for file in placed {
+        if let Some(name) = name(file.relative) {
+            groups.entry(name).or_insert_with(Vec::new).push(
+                file.dest.to_path_buf(),
+            );
+        }
+    }
+    for files in groups.values_mut() {
+        files.sort();
+    }
+    groups
+}
+
+/// Names the album of a file after its folders, i.e. `Artist - Album` for
+/// `Artist/Album/01 Song.opus`.  Files directly in the destination have no album.
+///
+/// # Arguments
+///
+/// * `relative` - The file's path relative to the destination folder
+fn album_name(relative: &Path) -> Option<String> {
+    let folders: Vec<String> = relative
+        .parent()
+        .into_iter()
+        .flat_map(|parent| parent.components())
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    if folders.is_empty() {
+        None
+    } else {
+        Some(folders.join(" - "))
+    }
+}
+
+/// Names the artist of a file after its top-level folder.  Files directly in the destination
+/// have no artist.
+///
+/// # Arguments
+///
+/// * `relative` - The file's path relative to the destination folder
+fn artist_name(relative: &Path) -> Option<String> {
+    let mut components = relative.components();
+    match (components.next(), components.next()) {
+        (Some(artist), Some(_)) => Some(artist.as_os_str().to_string_lossy().into_owned()),
+        _ => None,
+    }
+}
+
+/// Lists the files whose source was modified within `age`, newest first
+///
+/// # Arguments
+///
+/// * `placed` - The files in the destination
+/// * `age` - How long ago a file may have been added
+fn recently_added(placed: &[Placed], age: Duration) -> Vec<PathBuf> {
+    let now = SystemTime::now();
+    let mut recent: Vec<(SystemTime, PathBuf)> = placed
+        .iter()
+        .filter_map(|file| {
+            fs::metadata(file.source)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .map(|modified| (modified, file.dest.to_path_buf()))
+        })
+        .filter(|&(modified, _)| {
+            now.duration_since(modified).map(|d| d <= age).unwrap_or(
+                true,
+            )
+        })
+        .collect();
+    recent.sort_by(|a, b| b.cmp(a));
+    recent.into_iter().map(|(_, dest)| dest).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Placed, album_name, artist_name, group};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_album_name() {
+        assert_eq!(
+            album_name(Path::new("Artist/Album/01 Song.opus")),
+            Some("Artist - Album".to_owned())
+        );
+        assert_eq!(album_name(Path::new("Song.opus")), None);
+    }
+
+    #[test]
+    fn test_artist_name() {
+        assert_eq!(
+            artist_name(Path::new("Artist/Album/01 Song.opus")),
+            Some("Artist".to_owned())
+        );
+        assert_eq!(artist_name(Path::new("Song.opus")), None);
+    }
+
+    #[test]
+    fn test_group() {
+        let placed = vec![
+            Placed {
+                source: Path::new("/src/A/X/2.flac"),
+                relative: Path::new("A/X/2.opus"),
+                dest: Path::new("/dest/A/X/2.opus"),
+            },
+            Placed {
+                source: Path::new("/src/A/X/1.flac"),
+                relative: Path::new("A/X/1.opus"),
+                dest: Path::new("/dest/A/X/1.opus"),
+            },
+            Placed {
+                source: Path::new("/src/A/Y/1.mp3"),
+                relative: Path::new("A/Y/1.mp3"),
+                dest: Path::new("/dest/A/Y/1.mp3"),
+            },
+        ];
+        let artists = group(&placed, artist_name);
+        assert_eq!(artists.len(), 1);
+        assert_eq!(
+            artists["A"],
+            vec![
+                PathBuf::from("/dest/A/X/1.opus"),
+                PathBuf::from("/dest/A/X/2.opus"),
+                PathBuf::from("/dest/A/Y/1.mp3"),
+            ]
+        );
+        let albums = group(&placed, album_name);
+        assert_eq!(albums.len(), 2);
+        assert_eq!(albums["A - Y"], vec![PathBuf::from("/dest/A/Y/1.mp3")]);
+    }
+}
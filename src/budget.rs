@@ -0,0 +1,542 @@
+pub use errors::*;
+
+use config::Config;
+use glob::Pattern;
+use musicfile::{Action, Probe};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use syncer::Planned;
+use toml;
+
+/// The reason given for files that are left out to fit in `max_size`
+pub const LEFT_OUT: &'static str = "max_size";
+
+/// The reason given for files that the current rotation doesn't choose
+pub const ROTATED_OUT: &'static str = "rotation";
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+/// What a rotation chooses at random
+pub enum Sample {
+    /// Whole album folders
+    Album,
+    /// Single tracks
+    Track,
+}
+
+impl Default for Sample {
+    fn default() -> Sample {
+        Sample::Album
+    }
+}
+
+/// How the files of a rotation are chosen, worked out from `config::Rotation`
+struct Selection {
+    /// The number of the current rotation period, which seeds the choice
+    seed: u64,
+    by: Sample,
+    weighted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// What decides which files are synced first when they don't all fit in `max_size`
+pub enum Priority {
+    /// Files from earlier entries of `files` come first, then files from `playlists`
+    Order,
+    /// Files with higher `rating` or `fmps_rating` tags come first
+    Rating,
+    /// Files whose source changed most recently come first
+    Recent,
+    /// Files with more plays in `stats_file` come first
+    Plays,
+}
+
+impl Priority {
+    /// Reads a priority from its name in the config
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - One of `order`, `rating`, `recent` or `plays`
+    pub fn parse(name: &str) -> Result<Priority> {
+        match name {
+            "order" => Ok(Priority::Order),
+            "rating" => Ok(Priority::Rating),
+            "recent" => Ok(Priority::Recent),
+            "plays" => Ok(Priority::Plays),
+            _ => bail!("{} is not order, rating, recent or plays", name),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+/// The layout of `stats_file`
+struct Stats {
+    /// Play counts, by path relative to `source_folder`
+    #[serde(default)]
+    plays: HashMap<String, u64>,
+}
+
+/// Reads a size with an optional unit, i.e. `64GB`, `500 MiB` or `1000000`
+///
+/// # Arguments
+///
+/// * `text` - The size
+pub fn parse_size(text: &str) -> Result<u64> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_digit(10) && c != '.').unwrap_or(
+        text.len(),
+    );
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().chain_err(
+        || format!("{} does not start with a number", text),
+    )?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        "tb" => 1000 * 1000 * 1000 * 1000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        unit => bail!("{} is not a unit of size, like MB or GiB", unit),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// The files that were left out of a plan to fit in `max_size`
+pub struct LeftOut {
+    /// The number of files left out
+    pub files: usize,
+    /// Their estimated size in the destination
+    pub bytes: u64,
+}
+
+/// Limits a sync to `max_size`, choosing which files to sync by the `priority` settings
+pub struct Budget {
+    max_size: u64,
+    priorities: Vec<Priority>,
+    source_folder: PathBuf,
+    files: Vec<String>,
+    plays: HashMap<PathBuf, u64>,
+    rotation: Option<Selection>,
+}
+
+impl Budget {
+    /// Creates the budget from the config, if `max_size` is set
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The user-specified config
+    pub fn new(config: &Config) -> Result<Option<Budget>> {
+        let max_size = match config.max_size {
+            Some(ref max_size) => parse_size(max_size)?,
+            None => return Ok(None),
+        };
+        let priorities = config
+            .priority
+            .iter()
+            .flat_map(|p| p)
+            .map(|p| Priority::parse(p))
+            .collect::<Result<Vec<Priority>>>()?;
+
+        let mut plays = HashMap::new();
+        if let Some(ref stats_file) = config.stats_file {
+            let mut contents = String::new();
+            File::open(stats_file)
+                .and_then(|mut f| f.read_to_string(&mut contents))
+                .chain_err(|| format!("Unable to read {}", stats_file))?;
+            let stats: Stats = toml::from_str(&contents).chain_err(|| {
+                format!("Unable to parse {}", stats_file)
+            })?;
+            for (path, count) in stats.plays {
+                plays.insert(Path::new(&config.source_folder).join(path), count);
+            }
+        }
+
+        let rotation = config.rotation.as_ref().map(|rotation| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            Selection {
+                seed: now / (rotation.period_days * 24 * 60 * 60),
+                by: rotation.by,
+                weighted: rotation.weighted,
+            }
+        });
+
+        Ok(Some(Budget {
+            max_size: max_size,
+            priorities: priorities,
+            source_folder: PathBuf::from(&config.source_folder),
+            files: config.files.clone(),
+            plays: plays,
+            rotation: rotation,
+        }))
+    }
+
+    /// Leaves files out of a plan, lowest priority first, until the rest fit in `max_size`.
+    /// Files that are already synced count towards it too, and are left out (so that
+    /// `Syncer::remove_left_out` removes them) if higher priority files need the room.  A file
+    /// that doesn't fit is skipped, but smaller files after it may still fit.
+    ///
+    /// With a rotation, files are chosen in a random order instead, which stays the same for
+    /// the whole rotation period.  Albums are chosen or left out as a whole.
+    ///
+    /// # Arguments
+    ///
+    /// * `planned` - The plan, which is changed in place
+    /// * `bit_rate` - The bit rate of conversions in KB/s, for estimating their size
+    pub fn fit(&self, planned: &mut [Planned], bit_rate: usize) -> LeftOut {
+        let mut candidates: Vec<(usize, Rank)> = planned
+            .iter()
+            .enumerate()
+            .filter(|&(_, planned)| match planned.action {
+                Ok(ref action) => action.dest().is_some(),
+                Err(_) => false,
+            })
+            .map(|(i, planned)| (i, self.rank(planned)))
+            .collect();
+        self.score(&mut candidates);
+        candidates.sort_by(|a, b| self.compare(&a.1, &b.1));
+
+        let reason = if self.rotation.is_some() {
+            ROTATED_OUT
+        } else {
+            LEFT_OUT
+        };
+        let mut total = 0;
+        let mut left_out = LeftOut { files: 0, bytes: 0 };
+        let mut start = 0;
+        while start < candidates.len() {
+            let end = candidates[start..]
+                .iter()
+                .position(|c| c.1.group != candidates[start].1.group)
+                .map_or(candidates.len(), |n| start + n);
+            let group = &candidates[start..end];
+            let size: u64 = group
+                .iter()
+                .map(|&(i, _)| planned[i].estimated_size(bit_rate))
+                .sum();
+            if total + size <= self.max_size {
+                total += size;
+            } else {
+                for &(i, _) in group {
+                    info!("Left out {} by {}", planned[i].file.filename.display(), reason);
+                    planned[i].action = Ok(Action::Exclude(reason.to_owned()));
+                    left_out.files += 1;
+                }
+                left_out.bytes += size;
+            }
+            start = end;
+        }
+        left_out
+    }
+
+    /// Works out everything that the priorities might compare a file by
+    ///
+    /// # Arguments
+    ///
+    /// * `planned` - The file and what is planned for it
+    fn rank(&self, planned: &Planned) -> Rank {
+        let filename = &planned.file.filename;
+        let order = self.files
+            .iter()
+            .position(|entry| {
+                let entry = self.source_folder.join(entry);
+                filename.starts_with(&entry) ||
+                    entry.to_str().and_then(|e| Pattern::new(e).ok()).map_or(
+                        false,
+                        |p| p.matches_path(filename),
+                    )
+            })
+            .unwrap_or_else(|| self.files.len());
+        let rating = planned.probe.as_ref().and_then(rating);
+        let group = match self.rotation {
+            Some(Selection { by: Sample::Album, .. }) => {
+                filename.parent().unwrap_or(filename).to_path_buf()
+            }
+            _ => filename.clone(),
+        };
+        Rank {
+            group: group,
+            score: 0.0,
+            order: order,
+            rating: rating,
+            modified: fs::metadata(filename).and_then(|m| m.modified()).ok(),
+            plays: self.plays.get(filename).cloned().unwrap_or(0),
+        }
+    }
+
+    /// Gives each file its random score in a rotation.  The files of a group share one score,
+    /// weighted by their mean rating, so that an album is chosen or left out as a whole.
+    ///
+    /// # Arguments
+    ///
+    /// * `ranks` - The ranks of the files in the plan, which are changed in place
+    fn score(&self, ranks: &mut [(usize, Rank)]) {
+        let rotation = match self.rotation {
+            Some(ref rotation) => rotation,
+            None => return,
+        };
+        let mut ratings: HashMap<PathBuf, (f64, usize)> = HashMap::new();
+        for &(_, ref rank) in ranks.iter() {
+            let total = ratings.entry(rank.group.clone()).or_insert((0.0, 0));
+            total.0 += rank.rating.unwrap_or(0.5);
+            total.1 += 1;
+        }
+        for &mut (_, ref mut rank) in ranks.iter_mut() {
+            // Weighted sampling without replacement (Efraimidis and Spirakis): higher rated
+            // groups get higher scores more often
+            let weight = if rotation.weighted {
+                let (sum, count) = ratings[&rank.group];
+                sum / count as f64 + 0.1
+            } else {
+                1.0
+            };
+            rank.score = random(rotation.seed, &rank.group).powf(1.0 / weight);
+        }
+    }
+
+    /// Compares two files by each priority in turn, keeping the plan's path order for ties.  A
+    /// rotation compares them by their random score instead, keeping albums together.
+    ///
+    /// # Arguments
+    ///
+    /// * `a` - The rank of the first file
+    /// * `b` - The rank of the second file
+    fn compare(&self, a: &Rank, b: &Rank) -> Ordering {
+        if self.rotation.is_some() {
+            return b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.group.cmp(&b.group));
+        }
+        for priority in &self.priorities {
+            let ordering = match *priority {
+                Priority::Order => a.order.cmp(&b.order),
+                Priority::Rating => {
+                    b.rating.partial_cmp(&a.rating).unwrap_or(Ordering::Equal)
+                }
+                Priority::Recent => b.modified.cmp(&a.modified),
+                Priority::Plays => b.plays.cmp(&a.plays),
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+/// What a file is prioritized by
+struct Rank {
+    /// The files that are chosen or left out together, i.e. the album with a rotation by album
+    group: PathBuf,
+    /// The random score of `group` in a rotation, from `Budget::score`
+    score: f64,
+    order: usize,
+    rating: Option<f64>,
+    modified: Option<SystemTime>,
+    plays: u64,
+}
+
+/// A random number above 0 and up to 1, which is the same every time for the same seed and
+/// path
+///
+/// # Arguments
+///
+/// * `seed` - The number of the rotation period
+/// * `path` - The file or album
+fn random(seed: u64, path: &Path) -> f64 {
+    let mut hasher = Sha256::default();
+    hasher.input(seed.to_string().as_bytes());
+    hasher.input(b"\0");
+    hasher.input(path.to_string_lossy().as_bytes());
+    let hash = hasher.result();
+    let number = hash.iter().take(8).fold(0u64, |n, &b| n << 8 | u64::from(b));
+    (number as f64 + 1.0) / (u64::max_value() as f64 + 1.0)
+}
+
+/// Reads a file's rating from its tags, from 0 to 1.  `fmps_rating` is already from 0 to 1,
+/// while `rating` may be out of 5, 100 or 255 depending on the tagger.
+///
+/// # Arguments
+///
+/// * `probe` - What was read from the file
+fn rating(probe: &Probe) -> Option<f64> {
+    if let Some(rating) = probe.tags.get("fmps_rating").and_then(|r| r.parse().ok()) {
+        return Some(rating);
+    }
+    probe.tags.get("rating").and_then(|r| r.parse::<f64>().ok()).map(
+        |rating| if rating <= 5.0 {
+            rating / 5.0
+        } else if rating <= 100.0 {
+            rating / 100.0
+        } else {
+            rating / 255.0
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use musicfile::{Action, Musicfile};
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use super::{Budget, LEFT_OUT, Priority, ROTATED_OUT, Rank, Sample, Selection, parse_size,
+                random};
+    use syncer::Planned;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000").unwrap(), 1000);
+        assert_eq!(parse_size("64GB").unwrap(), 64_000_000_000);
+        assert_eq!(parse_size("1.5 KiB").unwrap(), 1536);
+        assert_eq!(parse_size("2 mib").unwrap(), 2 * 1024 * 1024);
+        assert!(parse_size("GB").is_err());
+        assert!(parse_size("12 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_parse_priority() {
+        assert_eq!(Priority::parse("plays").unwrap(), Priority::Plays);
+        assert!(Priority::parse("loudness").is_err());
+    }
+
+    #[test]
+    fn test_fit() {
+        let budget = Budget {
+            max_size: 1000 * 1000,
+            priorities: vec![Priority::Order],
+            source_folder: PathBuf::from("test-files"),
+            files: vec!["folder2".to_owned(), "folder1".to_owned()],
+            plays: HashMap::new(),
+            rotation: None,
+        };
+        let copy = |path: &str| {
+            let filename = PathBuf::from(path);
+            Planned {
+                action: Ok(Action::Copy(filename.clone())),
+                file: Musicfile { filename: filename },
+                probe: None,
+            }
+        };
+        let mut planned = vec![
+            copy("test-files/folder1/How Doth The Little Crocodile.mp3"),
+            copy("test-files/folder2/Synth Synth Short.mp3"),
+        ];
+
+        let left_out = budget.fit(&mut planned, 320);
+        assert_eq!(left_out.files, 1);
+        assert_eq!(left_out.bytes, 924358);
+        assert_eq!(
+            planned[0].action.as_ref().unwrap(),
+            &Action::Exclude(LEFT_OUT.to_owned())
+        );
+        assert!(planned[1].action.as_ref().unwrap().dest().is_some());
+    }
+
+    #[test]
+    fn test_random() {
+        let album = Path::new("Artist/Album");
+        let number = random(1, album);
+        assert!(number > 0.0 && number <= 1.0);
+        assert_eq!(random(1, album).to_bits(), number.to_bits());
+        assert!(random(2, album).to_bits() != number.to_bits());
+    }
+
+    #[test]
+    fn test_fit_rotation() {
+        let budget = Budget {
+            max_size: 1000 * 1000,
+            priorities: Vec::new(),
+            source_folder: PathBuf::from("test-files"),
+            files: Vec::new(),
+            plays: HashMap::new(),
+            rotation: Some(Selection {
+                seed: 7,
+                by: Sample::Album,
+                weighted: false,
+            }),
+        };
+        let plan = || {
+            vec![
+                "test-files/Synth Synth Short.mp3",
+                "test-files/folder1/How Doth The Little Crocodile.mp3",
+                "test-files/folder2/Synth Synth Short.mp3",
+            ].into_iter()
+                .map(|path| {
+                    let filename = PathBuf::from(path);
+                    Planned {
+                        action: Ok(Action::Copy(filename.clone())),
+                        file: Musicfile { filename: filename },
+                        probe: None,
+                    }
+                })
+                .collect::<Vec<Planned>>()
+        };
+        let chosen = |planned: &[Planned]| -> Vec<bool> {
+            planned
+                .iter()
+                .map(|p| p.action.as_ref().unwrap() != &Action::Exclude(ROTATED_OUT.to_owned()))
+                .collect()
+        };
+
+        let mut first = plan();
+        let left_out = budget.fit(&mut first, 320);
+        assert!(left_out.files >= 1);
+        let mut second = plan();
+        budget.fit(&mut second, 320);
+        assert_eq!(chosen(&first), chosen(&second));
+    }
+
+    #[test]
+    fn test_score_weighted_album() {
+        let budget = Budget {
+            max_size: 1000 * 1000,
+            priorities: Vec::new(),
+            source_folder: PathBuf::from("test-files"),
+            files: Vec::new(),
+            plays: HashMap::new(),
+            rotation: Some(Selection {
+                seed: 7,
+                by: Sample::Album,
+                weighted: true,
+            }),
+        };
+        let rank = |group: &str, rating| {
+            Rank {
+                group: PathBuf::from(group),
+                score: 0.0,
+                order: 0,
+                rating: rating,
+                modified: None,
+                plays: 0,
+            }
+        };
+        let mut ranks = vec![
+            (0, rank("Artist/Album", Some(1.0))),
+            (1, rank("Artist/Album", Some(0.2))),
+            (2, rank("Artist/Album", None)),
+            (3, rank("Other/Album", Some(1.0))),
+        ];
+        budget.score(&mut ranks);
+
+        // The tracks of an album share one score, weighted by their mean rating
+        let weight = (1.0 + 0.2 + 0.5) / 3.0 + 0.1;
+        let score = random(7, Path::new("Artist/Album")).powf(1.0 / weight);
+        assert_eq!(ranks[0].1.score.to_bits(), score.to_bits());
+        assert_eq!(ranks[1].1.score.to_bits(), score.to_bits());
+        assert_eq!(ranks[2].1.score.to_bits(), score.to_bits());
+        let score = random(7, Path::new("Other/Album")).powf(1.0 / (1.0 + 0.1));
+        assert_eq!(ranks[3].1.score.to_bits(), score.to_bits());
+    }
+}
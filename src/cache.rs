@@ -0,0 +1,194 @@
+pub use errors::*;
+
+use config::ConvertProfile;
+use musicfile::PARTIAL_PREFIX;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// A folder of converted files, kept by the content of their source, the profile that converted
+/// them and the version of casm, so that syncs to different destinations (or by different
+/// people sharing the folder) only convert a file once
+pub struct Cache {
+    folder: PathBuf,
+}
+
+impl Cache {
+    /// Uses a folder as a cache, creating it when something is first stored
+    ///
+    /// # Arguments
+    ///
+    /// * `folder` - The cache folder
+    pub fn new(folder: &Path) -> Cache {
+        Cache { folder: folder.to_path_buf() }
+    }
+
+    /// Works out the cache key of converting a file with a profile.  The key changes if the
+    /// source's contents, the profile's settings or the casm version change, since any of these
+    /// can change the output.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The music file that is converted
+    /// * `profile` - The profile it is converted with
+    pub fn key(source: &Path, profile: &ConvertProfile) -> Result<String> {
+        let content = content_hash(source)?;
+        let mut hasher = Sha256::default();
+        hasher.input(content.as_bytes());
+        hasher.input(b"\0");
+        hasher.input(profile.fingerprint().as_bytes());
+        hasher.input(b"\0");
+        hasher.input(env!("CARGO_PKG_VERSION").as_bytes());
+        Ok(hex(&hasher.result()))
+    }
+
+    /// The path that the output with a key is stored at.  Outputs are spread over folders named
+    /// by the start of their key, so that no one folder gets too big.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key from `key`
+    /// * `extension` - The extension of the output, i.e. `opus`
+    pub fn path(&self, key: &str, extension: &str) -> PathBuf {
+        self.folder.join(&key[..2]).join(key).with_extension(extension)
+    }
+
+    /// Copies the cached output with a key to a path, returning whether there was one
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key from `key`
+    /// * `extension` - The extension of the output
+    /// * `dest` - Where to copy it to
+    pub fn fetch(&self, key: &str, extension: &str, dest: &Path) -> Result<bool> {
+        let cached = self.path(key, extension);
+        if !cached.is_file() {
+            return Ok(false);
+        }
+        fs::copy(&cached, dest).chain_err(|| {
+            format!("Could not copy {} from the cache", cached.display())
+        })?;
+        Ok(true)
+    }
+
+    /// Removes the output with a key, i.e. once a copy of it has failed verification, so that
+    /// it is converted and stored again
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key from `key`
+    /// * `extension` - The extension of the output
+    pub fn remove(&self, key: &str, extension: &str) -> Result<()> {
+        let cached = self.path(key, extension);
+        if !cached.exists() {
+            return Ok(());
+        }
+        fs::remove_file(&cached).chain_err(|| {
+            format!("Could not remove {} from the cache", cached.display())
+        })
+    }
+
+    /// Stores a converted file under a key.  It is copied to a partial file first and renamed
+    /// into place, so another sync sharing the cache never sees half of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key from `key`
+    /// * `extension` - The extension of the output
+    /// * `file` - The converted file
+    pub fn store(&self, key: &str, extension: &str, file: &Path) -> Result<()> {
+        let cached = self.path(key, extension);
+        let folder = cached.parent().unwrap_or(&self.folder).to_path_buf();
+        let partial = folder.join(format!("{}{}.{}", PARTIAL_PREFIX, key, extension));
+        let result = fs::create_dir_all(&folder)
+            .and_then(|_| fs::copy(file, &partial))
+            .and_then(|_| fs::rename(&partial, &cached))
+            .chain_err(|| format!("Could not store {} in the cache", file.display()));
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result
+    }
+}
+
+/// Hashes the contents of a file, as lowercase hexadecimal SHA-256
+///
+/// # Arguments
+///
+/// * `path` - The file
+pub fn content_hash(path: &Path) -> Result<String> {
+    let mut file = File::open(path).chain_err(
+        || format!("Unable to read {}", path.display()),
+    )?;
+    let mut hasher = Sha256::default();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).chain_err(
+            || format!("Unable to read {}", path.display()),
+        )?;
+        if read == 0 {
+            break;
+        }
+        hasher.input(&buffer[..read]);
+    }
+    Ok(hex(&hasher.result()))
+}
+
+/// Formats bytes as lowercase hexadecimal
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes, i.e. a hash
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use config::ConvertProfile;
+    use std::fs;
+    use std::path::Path;
+    use super::Cache;
+
+    fn profile(bit_rate: usize) -> ConvertProfile {
+        ConvertProfile {
+            target_format: "OPUS".to_owned(),
+            acceptable_formats: vec!["quality:lossy".to_owned()],
+            bit_rate: bit_rate,
+            reconvert_on_change: true,
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        let source = Path::new("test-files/folder1/How Doth The Little Crocodile.mp3");
+        let key = Cache::key(source, &profile(320)).unwrap();
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, Cache::key(source, &profile(320)).unwrap());
+        assert!(key != Cache::key(source, &profile(128)).unwrap());
+    }
+
+    #[test]
+    fn test_store_and_fetch() {
+        let folder = Path::new("/tmp/casm-test-cache");
+        let _ = fs::remove_dir_all(folder);
+        let cache = Cache::new(folder);
+        let source = Path::new("test-files/folder1/How Doth The Little Crocodile.mp3");
+        let key = Cache::key(source, &profile(320)).unwrap();
+        let fetched = folder.join("fetched.mp3");
+
+        assert!(!cache.fetch(&key, "mp3", &fetched).unwrap());
+        cache.store(&key, "mp3", source).unwrap();
+        assert!(cache.path(&key, "mp3").starts_with(folder.join(&key[..2])));
+        assert!(cache.fetch(&key, "mp3", &fetched).unwrap());
+        assert_eq!(
+            fs::metadata(&fetched).unwrap().len(),
+            fs::metadata(source).unwrap().len()
+        );
+
+        cache.remove(&key, "mp3").unwrap();
+        assert!(!cache.fetch(&key, "mp3", &fetched).unwrap());
+        cache.remove(&key, "mp3").unwrap();
+    }
+}
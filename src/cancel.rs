@@ -0,0 +1,75 @@
+use manifest::Manifest;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Clone, Debug, Default)]
+/// A flag that asks a sync to stop.  Clones share the flag, so one can be handed to a signal
+/// handler while the syncer checks another.
+pub struct Cancel {
+    cancelled: Arc<AtomicBool>,
+    /// The manifest of the sync in progress, if there is one
+    progress: Arc<Mutex<Option<Manifest>>>,
+}
+
+impl Cancel {
+    /// Creates a flag that has not been raised
+    pub fn new() -> Cancel {
+        Cancel::default()
+    }
+
+    /// Asks the sync to stop.  Files that have not started are skipped, and conversions in
+    /// progress are abandoned.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the sync has been asked to stop
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Saves the manifest of the sync in progress, so that the files it has finished are known
+    /// even if the process exits without waiting for it.  Does nothing if no sync is running.
+    pub fn save_progress(&self) {
+        if let Some(ref manifest) = *self.progress.lock().unwrap() {
+            if let Err(e) = manifest.save() {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// Where the sync in progress keeps its manifest, for `save_progress` to find
+    pub(crate) fn progress(&self) -> &Mutex<Option<Manifest>> {
+        &self.progress
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use manifest::{MANIFEST_FILENAME, Manifest};
+    use std::fs;
+    use std::path::Path;
+    use super::Cancel;
+
+    #[test]
+    fn test_shared() {
+        let cancel = Cancel::new();
+        let handle = cancel.clone();
+        assert!(!cancel.is_cancelled());
+        handle.cancel();
+        assert!(cancel.is_cancelled());
+    }
+
+    #[test]
+    fn test_save_progress() {
+        let dest = Path::new("/tmp/casm-test-progress");
+        let _ = fs::remove_dir_all(dest);
+        let cancel = Cancel::new();
+        cancel.save_progress();
+        assert!(!dest.join(MANIFEST_FILENAME).exists());
+
+        *cancel.progress().lock().unwrap() = Some(Manifest::new(dest));
+        cancel.clone().save_progress();
+        assert!(dest.join(MANIFEST_FILENAME).is_file());
+    }
+}
@@ -19,12 +19,25 @@ impl<'a> Codec<'a> {
     ///
     /// * 'acceptable_formats' - The list of acceptable formats provided in the config
     pub fn is_acceptable(&self, acceptable_formats: &[String]) -> bool {
+        self.acceptable_rule(acceptable_formats).is_some()
+    }
+
+    /// Finds the entry in the user's acceptable formats that this codec matches, preferring one
+    /// that names the codec over a quality
+    ///
+    /// # Arguments
+    ///
+    /// * 'acceptable_formats' - The list of acceptable formats provided in the config
+    pub fn acceptable_rule<'b>(&self, acceptable_formats: &'b [String]) -> Option<&'b str> {
         let quality = format!(
             "quality:{}",
             if self.lossless { "lossless" } else { "lossy" }
         );
-        acceptable_formats.contains(&self.name.to_string()) ||
-            acceptable_formats.contains(&quality.to_string())
+        acceptable_formats
+            .iter()
+            .find(|format| *format == self.name)
+            .or_else(|| acceptable_formats.iter().find(|format| **format == quality))
+            .map(|format| format.as_str())
     }
 }
 
@@ -77,6 +90,21 @@ mod tests {
         assert_eq!(codec.is_acceptable(&acceptable_formats), true)
     }
 
+    #[test]
+    fn test_acceptable_rule() {
+        let codec = Codec {
+            name: "OPUS",
+            lossless: false,
+            extension: "opus",
+        };
+        let acceptable_formats = vec!["quality:lossy".to_owned(), "OPUS".to_owned()];
+        assert_eq!(codec.acceptable_rule(&acceptable_formats), Some("OPUS"));
+        let acceptable_formats = vec!["MP3".to_owned(), "quality:lossy".to_owned()];
+        assert_eq!(codec.acceptable_rule(&acceptable_formats), Some("quality:lossy"));
+        let acceptable_formats = vec!["quality:lossless".to_owned()];
+        assert_eq!(codec.acceptable_rule(&acceptable_formats), None);
+    }
+
     #[test]
     fn test_unacceptable_type() {
         let codec = Codec {
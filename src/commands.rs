@@ -0,0 +1,524 @@
+pub use errors::*;
+
+use config::{Config, Verification};
+use exclude::Exclude;
+use ignore::{self, Ignore};
+use json::JsonLines;
+use manifest::Manifest;
+use musicfile::{Action, Musicfile, Probe, remove_empty_parents};
+use notify::{self, DebouncedEvent, RecursiveMode, Watcher};
+use observer::{Observer, Progress, Quiet};
+use sniff::{self, Detection};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+use summary::Summary;
+use support::Support;
+use syncer::Syncer;
+use walkdir::WalkDir;
+
+/// How long, in seconds, changes must settle before `watch` syncs them
+const WATCH_DEBOUNCE: u64 = 2;
+
+/// Converts and copies the configured music into the destination, then writes any playlists
+///
+/// # Arguments
+///
+/// * `syncer` - The syncer for the user-specified config
+/// * `json` - Write JSON Lines records instead of showing a progress bar
+pub fn sync(syncer: &Syncer, json: bool) -> Result<()> {
+    let observer: Box<Observer> = if json {
+        Box::new(JsonLines::new(&syncer.config().convert_profile.target_format))
+    } else {
+        Box::new(Progress::new())
+    };
+    let report = syncer.sync(&*observer)?;
+
+    let summary = Summary::new(&report);
+    if !json {
+        println!("{}", summary);
+    }
+    if report.cancelled {
+        bail!("Sync was interrupted; run it again to pick up where it stopped");
+    }
+    if report.out_of_space {
+        bail!(
+            "{} ran out of space; free some up or set max_size, then sync again",
+            syncer.config().dest_folder
+        );
+    }
+    if summary.failed > 0 {
+        bail!("{} files failed to sync", summary.failed);
+    }
+    Ok(())
+}
+
+/// Syncs, then watches `source_folder` and syncs the files that are added, changed, moved or
+/// removed, until interrupted.  Changed files replace their synced copies, unlike in `sync`,
+/// which keeps any synced copy that is there.  With `max_size`, the whole library is planned
+/// again instead, so that it is still chosen from as a whole.  Playlists are only written by the
+/// first sync.
+///
+/// # Arguments
+///
+/// * `syncer` - The syncer for the user-specified config
+pub fn watch(syncer: &Syncer) -> Result<()> {
+    let cancel = syncer.cancel_handle();
+    if let Err(e) = sync(syncer, false) {
+        if cancel.is_cancelled() {
+            return Err(e);
+        }
+        warn!("{}", e);
+    }
+
+    let source_folder = &syncer.config().source_folder;
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, Duration::from_secs(WATCH_DEBOUNCE)).chain_err(
+        || "Unable to watch for changes",
+    )?;
+    watcher
+        .watch(source_folder, RecursiveMode::Recursive)
+        .chain_err(|| format!("Unable to watch {}", source_folder))?;
+    println!("Watching {} for changes", source_folder);
+
+    while !cancel.is_cancelled() {
+        let first = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => bail!("Stopped receiving changes"),
+        };
+
+        // Bursts of writes arrive together once they have settled down
+        let mut changed = Vec::new();
+        let mut removed = Vec::new();
+        for event in iter::once(first).chain(rx.try_iter()) {
+            match event {
+                DebouncedEvent::Create(path) |
+                DebouncedEvent::Write(path) => changed.push(path),
+                DebouncedEvent::Remove(path) => removed.push(path),
+                DebouncedEvent::Rename(from, to) => {
+                    removed.push(from);
+                    changed.push(to);
+                }
+                DebouncedEvent::Rescan => changed.push(PathBuf::from(source_folder)),
+                DebouncedEvent::Error(e, path) => {
+                    match path {
+                        Some(path) => warn!("{}: {}", path.display(), e),
+                        None => warn!("{}", e),
+                    }
+                }
+                DebouncedEvent::NoticeWrite(_) |
+                DebouncedEvent::NoticeRemove(_) |
+                DebouncedEvent::Chmod(_) => {}
+            }
+        }
+
+        // Files that were moved are synced first, so that their synced copies are moved too
+        // rather than removed.  A budget chooses from everything, so it needs the whole library.
+        let files = if syncer.config().max_size.is_some() && !changed.is_empty() {
+            syncer.scan()
+        } else {
+            syncer.scan_changed(&changed)
+        };
+        if !files.is_empty() {
+            let progress = Progress::new();
+            let mut plan = match syncer.plan(files, &progress) {
+                Ok(plan) => plan,
+                // Planning only fails when it is cancelled
+                Err(_) => break,
+            };
+            syncer.replace_outdated(&mut plan);
+            match syncer.remove_left_out(&plan) {
+                Ok(deleted) => {
+                    for dest in deleted {
+                        println!("Removed {}", dest.display());
+                    }
+                }
+                Err(e) => warn!("{}", e),
+            }
+            match syncer.check_space(&plan) {
+                Ok(()) => {
+                    let report = syncer.execute(plan, &progress);
+                    println!("{}", Summary::new(&report));
+                    if report.out_of_space {
+                        warn!("{} ran out of space", syncer.config().dest_folder);
+                    }
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+
+        if !removed.is_empty() {
+            match syncer.remove_deleted(&removed) {
+                Ok(deleted) => {
+                    for dest in deleted {
+                        println!("Removed {}", dest.display());
+                    }
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints what `sync` would do with each file, without doing it
+///
+/// # Arguments
+///
+/// * `syncer` - The syncer for the user-specified config
+pub fn plan(syncer: &Syncer) -> Result<()> {
+    for planned in syncer.plan(syncer.scan(), &Quiet)? {
+        match planned.action {
+            Ok(action) => println!("{}: {}", planned.file.filename.display(), action),
+            Err(e) => println!("{}: error: {}", planned.file.filename.display(), e),
+        }
+    }
+    Ok(())
+}
+
+/// Prints the stream details and tags of each file, along with the rules that decide what
+/// `sync` would do with it and the destination it would produce
+///
+/// # Arguments
+///
+/// * `syncer` - The syncer for the user-specified config
+/// * `files` - The paths to probe, either as given or relative to `source_folder`
+pub fn probe(syncer: &Syncer, files: &[&str]) -> Result<()> {
+    let config = syncer.config();
+    let exclude = syncer.exclude();
+    let mut ignore = Ignore::new(&config.source_folder);
+    for file in files {
+        let filename = if Path::new(file).exists() {
+            PathBuf::from(file)
+        } else {
+            Path::new(&config.source_folder).join(file)
+        };
+        let musicfile = Musicfile { filename: filename };
+        println!("{}", musicfile.filename.display());
+
+        match musicfile.probe() {
+            Ok(probe) => print_probe(&probe, config, exclude),
+            Err(e) => println!("  error:       {}", e),
+        }
+
+        if ignore.is_ignored(&musicfile.filename, false) {
+            println!("  ignored by:  {}", ignore::IGNORE_FILENAME);
+        }
+        if let Some(rule) = exclude.matches_path(&musicfile.filename) {
+            println!("  excluded by: {}", rule);
+        }
+
+        match musicfile.plan(
+            &config.source_folder,
+            &config.dest_folder,
+            &config.convert_profile,
+            exclude,
+        ) {
+            Ok(action) => println!("  action:      {}", action),
+            Err(e) => println!("  action:      error: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Prints what ffmpeg found in a file, and how the config treats its codec and tags
+///
+/// # Arguments
+///
+/// * `probe` - The stream information and tags of the music file
+/// * `config` - The user-specified config
+/// * `exclude` - The rules to exclude
+fn print_probe(probe: &Probe, config: &Config, exclude: &Exclude) {
+    println!("  container:   {}", probe.container);
+    println!("  codec:       {}", probe.codec.name());
+    match ::find_codec(probe.codec.name()) {
+        Some(codec) => {
+            println!(
+                "  quality:     {}",
+                if codec.lossless { "lossless" } else { "lossy" }
+            );
+            println!("  extension:   {}", codec.extension);
+            match codec.acceptable_rule(&config.convert_profile.acceptable_formats) {
+                Some(rule) => println!("  acceptable:  yes, by {}", rule),
+                None => {
+                    println!(
+                        "  acceptable:  no, converts to {}",
+                        config.convert_profile.target_format
+                    )
+                }
+            }
+        }
+        None => println!("  acceptable:  no, not a supported codec"),
+    }
+    match probe.bit_rate {
+        Some(bit_rate) => println!("  bit rate:    {} kb/s", bit_rate / 1000),
+        None => println!("  bit rate:    unknown"),
+    }
+    println!("  sample rate: {} Hz", probe.sample_rate);
+    println!("  channels:    {}", probe.channels);
+    match probe.duration {
+        Some(duration) => {
+            let seconds = duration.as_secs();
+            println!("  duration:    {}:{:02}", seconds / 60, seconds % 60)
+        }
+        None => println!("  duration:    unknown"),
+    }
+
+    let tags: BTreeMap<_, _> = probe.tags.iter().collect();
+    if !tags.is_empty() {
+        println!("  tags:");
+        for (key, value) in tags {
+            println!("    {}: {}", key, value);
+        }
+    }
+
+    if let Some(rule) = exclude.matches_probe(probe) {
+        println!("  excluded by: {}", rule);
+    }
+}
+
+/// Prints every codec that casm can handle, with its quality and extension, and whether the
+/// linked ffmpeg can decode, encode and mux it
+pub fn codecs() -> Result<()> {
+    let mut codecs: Vec<_> = ::ALL_CODECS.values().collect();
+    codecs.sort_by_key(|codec| codec.name);
+    println!(
+        "{:<18} {:<9} {:<9} {:<7} {:<7} {}",
+        "CODEC",
+        "QUALITY",
+        "EXTENSION",
+        "DECODE",
+        "ENCODE",
+        "MUXER"
+    );
+    for codec in codecs {
+        let support = Support::check(codec);
+        let yes_no = |b| if b { "yes" } else { "no" };
+        let muxer = match support.muxer {
+            Some((name, true)) => name,
+            Some((name, false)) => format!("{} (cannot hold {})", name, codec.name),
+            None => "none".to_owned(),
+        };
+        println!(
+            "{:<18} {:<9} {:<9} {:<7} {:<7} {}",
+            codec.name,
+            if codec.lossless { "lossless" } else { "lossy" },
+            codec.extension,
+            yes_no(support.decoder),
+            yes_no(support.encoder),
+            muxer
+        );
+    }
+    Ok(())
+}
+
+/// Prints every problem with the config
+///
+/// # Arguments
+///
+/// * `config` - The user-specified config
+pub fn check_config(config: &Config) -> Result<()> {
+    let problems = config.validate();
+    if problems.is_empty() {
+        println!("Config OK");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    bail!("Found {} problems in the config", problems.len())
+}
+
+/// Removes music files from the destination that `sync` would not put there.  Outputs of
+/// source files that can't be read are kept, whatever their extension.
+///
+/// # Arguments
+///
+/// * `syncer` - The syncer for the user-specified config
+/// * `dry_run` - Only print the files that would be removed
+pub fn clean(syncer: &Syncer, dry_run: bool) -> Result<()> {
+    let config = syncer.config();
+    let cancel = syncer.cancel_handle();
+    let mut expected = HashSet::new();
+    let mut unreadable = HashSet::new();
+    // A partial plan would make the files it missed look like orphans
+    for planned in syncer.plan(syncer.scan(), &Quiet)? {
+        let file = planned.file;
+        match planned.action {
+            Ok(action) => {
+                if let Some(dest) = action.dest() {
+                    expected.insert(dest.to_path_buf());
+                }
+                // `sync` moves this rather than making it again
+                if let Action::Move(ref from, _) = action {
+                    expected.insert(from.clone());
+                }
+            }
+            Err(_) => {
+                if let Ok(relative) = file.filename.strip_prefix(&config.source_folder) {
+                    unreadable.insert(Path::new(&config.dest_folder).join(relative).with_extension(
+                        "",
+                    ));
+                }
+            }
+        }
+    }
+
+    let dest_folder = Path::new(&config.dest_folder);
+    let orphans: Vec<PathBuf> = WalkDir::new(dest_folder)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.path().to_path_buf())
+        .filter(|path| sniff::is_audio(path, Detection::Fast))
+        .filter(|path| {
+            !expected.contains(path) && !unreadable.contains(&path.with_extension(""))
+        })
+        .collect();
+
+    let count = orphans.len();
+    for orphan in orphans {
+        if cancel.is_cancelled() {
+            bail!(ErrorKind::Cancelled);
+        }
+        println!("Removing {}", orphan.display());
+        if dry_run {
+            continue;
+        }
+        fs::remove_file(&orphan).chain_err(|| {
+            format!("Unable to remove {}", orphan.display())
+        })?;
+
+        remove_empty_parents(&orphan, dest_folder);
+    }
+
+    if dry_run {
+        println!("Would delete {} files", count);
+    } else {
+        println!("Deleted {} files", count);
+    }
+    Ok(())
+}
+
+/// Checks that every file `sync` would put in the destination is there and not empty.  Unless
+/// `quick` is set, copies are also compared with their sources, and conversions are decoded all
+/// the way through and compared with the length of their sources.
+///
+/// # Arguments
+///
+/// * `syncer` - The syncer for the user-specified config
+/// * `quick` - Only check that the files are there
+pub fn verify(syncer: &Syncer, quick: bool) -> Result<()> {
+    let config = syncer.config();
+    let source_folder = Path::new(&config.source_folder);
+    let dest_folder = Path::new(&config.dest_folder);
+    let manifest = Manifest::load(dest_folder).unwrap_or_else(|e| {
+        warn!("{}; telling conversions from copies by their extension", e);
+        Manifest::new(dest_folder)
+    });
+    let default = Verification::default();
+    let tolerance = config.verification.as_ref().unwrap_or(&default).tolerance;
+
+    let mut problems = 0;
+    for planned in syncer.plan(syncer.scan(), &Quiet)? {
+        let file = planned.file;
+        let duration = planned.probe.and_then(|probe| probe.duration);
+        let problem = match planned.action {
+            Ok(Action::Keep(dest)) => {
+                match fs::metadata(&dest) {
+                    Ok(ref metadata) if metadata.len() > 0 && quick => None,
+                    Ok(ref metadata) if metadata.len() > 0 => {
+                        let filename = &file.filename;
+                        let source = filename.strip_prefix(source_folder).unwrap_or(filename);
+                        // Conversions recorded before fingerprints were have none, so they
+                        // are told apart from copies by their extension like unrecorded files
+                        let converted = manifest
+                            .entries
+                            .get(source)
+                            .map_or(false, |entry| entry.fingerprint.is_some()) ||
+                            dest.extension() != filename.extension();
+                        match file.verify(&dest, converted, duration, tolerance) {
+                            Ok(()) => None,
+                            Err(e) => {
+                                let causes: Vec<String> =
+                                    e.iter().map(|e| e.to_string()).collect();
+                                Some(causes.join(": "))
+                            }
+                        }
+                    }
+                    _ => Some(format!("{} is empty", dest.display())),
+                }
+            }
+            Ok(ref action) => unsynced(action),
+            Err(e) => Some(format!("error: {}", e)),
+        };
+        if let Some(problem) = problem {
+            println!("{}: {}", file.filename.display(), problem);
+            problems += 1;
+        }
+    }
+
+    if problems > 0 {
+        bail!("{} files failed verification", problems);
+    }
+    println!("All files verified");
+    Ok(())
+}
+
+/// Says why a file that a sync would still write is not verified, or gives `None` if the sync
+/// would leave it alone.  A synced copy can be there and still be written again, i.e. after
+/// `convert_profile` changes.
+///
+/// # Arguments
+///
+/// * `action` - What a sync would do with the file
+fn unsynced(action: &Action) -> Option<String> {
+    match *action {
+        Action::Copy(ref dest) |
+        Action::Transcode(ref dest) if !dest.exists() => {
+            Some(format!("{} is missing", dest.display()))
+        }
+        Action::Copy(ref dest) => Some(format!("{} would be copied again", dest.display())),
+        Action::Transcode(ref dest) => {
+            Some(format!("{} would be converted again", dest.display()))
+        }
+        Action::Move(ref from, ref dest) => {
+            Some(format!("{} would be moved from {}", dest.display(), from.display()))
+        }
+        Action::Keep(_) |
+        Action::Exclude(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use musicfile::Action;
+    use std::path::PathBuf;
+    use super::unsynced;
+
+    #[test]
+    fn test_unsynced() {
+        let missing = PathBuf::from("/tmp/casm-test-unsynced/missing.opus");
+        assert_eq!(
+            unsynced(&Action::Transcode(missing.clone())),
+            Some(format!("{} is missing", missing.display()))
+        );
+
+        // A synced copy converted with other convert_profile settings is there, but stale
+        let stale = PathBuf::from("test-files/Synth Synth Short.mp3");
+        assert_eq!(
+            unsynced(&Action::Transcode(stale.clone())),
+            Some(format!("{} would be converted again", stale.display()))
+        );
+
+        assert_eq!(
+            unsynced(&Action::Move(stale.clone(), missing.clone())),
+            Some(format!("{} would be moved from {}", missing.display(), stale.display()))
+        );
+        assert_eq!(unsynced(&Action::Keep(stale)), None);
+    }
+}
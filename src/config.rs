@@ -1,6 +1,16 @@
+use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use budget::{self, Priority, Sample};
+use exclude::Predicate;
+use glob::Pattern;
+use logger;
+use playlist::Encoding;
+use regex::Regex;
+use sniff::Detection;
+use std::path::{Component, Path, PathBuf};
+use support;
 use xdg::BaseDirectories;
 use toml;
 pub use errors::*;
@@ -15,10 +25,59 @@ pub struct Config {
     pub dest_folder: String,
     /// The regex that matches files to exclude, usually for instrumental tracks
     pub exclude: Option<Vec<String>>,
+    /// Glob patterns, relative to `source_folder`, that match files to exclude
+    pub exclude_globs: Option<Vec<String>>,
+    /// Metadata rules (i.e. `genre = "Audiobook"` or `duration > 20m`) that exclude a file once
+    /// it has been probed
+    pub exclude_tags: Option<Vec<String>>,
     /// A list of files, folders, and glob patterns to convert (this will be unique'd later)
     pub files: Vec<String>,
+    /// A list of playlist files and glob patterns whose entries should be converted.  Playlists
+    /// may also be listed in `files`.
+    pub playlists: Option<Vec<String>>,
+    /// Whether music files are recognized by their extension (`fast`) or their contents
+    /// (`thorough`)
+    #[serde(default)]
+    pub detection: Detection,
+    /// The most detailed messages to log: `off`, `error`, `warn` (the default), `info`, `debug`
+    /// or `trace`.  Each `-v` logs one level more.
+    pub log_level: Option<String>,
+    /// A file that log messages are appended to
+    pub log_file: Option<String>,
+    /// Whether log messages are shown on stderr, above the progress bar.  Turning this off
+    /// with `log_file` set only writes them to the file.
+    #[serde(default = "default_log_stderr")]
+    pub log_stderr: bool,
+    /// A folder to keep converted files in, so that a file converted once with a profile is
+    /// copied from here by later syncs, even to other destinations
+    pub cache_folder: Option<String>,
+    /// The most room the synced music may take in `dest_folder`, i.e. `64GB` or `500 MiB`.  If
+    /// the selected music doesn't fit, the lowest `priority` files are left out.
+    pub max_size: Option<String>,
+    /// What decides which files are kept when they don't all fit in `max_size`, most important
+    /// first: `order` (of `files`), `rating`, `recent` or `plays`
+    pub priority: Option<Vec<String>>,
+    /// A TOML file with a `[plays]` table of play counts by path relative to `source_folder`,
+    /// for the `plays` priority
+    pub stats_file: Option<String>,
+    /// What to do when the files to sync look like they won't fit in the free space of
+    /// `dest_folder`
+    #[serde(default)]
+    pub space_check: SpaceCheck,
+    /// If present, a random selection of the music that fits in `max_size` is synced, and a
+    /// new one is chosen every rotation period
+    pub rotation: Option<Rotation>,
+    /// If present, each file is checked after it is written, and written again if it is
+    /// corrupt.  The settings are also used by `casm verify`.
+    pub verification: Option<Verification>,
     /// Conversion-specific settings
     pub convert_profile: ConvertProfile,
+    /// If present, playlists from `files` and `playlists` are rewritten to point at the synced
+    /// files and written to the destination
+    pub playlist_export: Option<PlaylistExport>,
+    /// If present, album, artist and recently added playlists of the synced files are written
+    /// to the destination
+    pub generate_playlists: Option<GeneratePlaylists>,
 }
 
 // Conversion options
@@ -38,8 +97,145 @@ pub struct ConvertProfile {
     pub acceptable_formats: Vec<String>,
     /// A target bit rate in KB/s (i.e. 320 or 128)
     pub bit_rate: usize,
+    /// Whether files that were converted with different settings are converted again
+    #[serde(default = "default_reconvert_on_change")]
+    pub reconvert_on_change: bool,
 }
 
+impl ConvertProfile {
+    /// The ffmpeg filter graph that converted audio is passed through
+    pub fn filter(&self) -> &str {
+        "anull"
+    }
+
+    /// Describes every setting that changes what a conversion writes, so that files converted
+    /// with other settings can be found.  Settings that only choose which files are converted,
+    /// like `acceptable_formats`, are left out.
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "codec={};bit_rate={};filter={};tags=copy",
+            self.target_format,
+            self.bit_rate,
+            self.filter()
+        )
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+/// How written files are checked
+pub struct Verification {
+    /// How many seconds the decoded length of a converted file may differ from its source's
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+    /// How many more times a file that fails the check is written
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+impl Default for Verification {
+    fn default() -> Verification {
+        Verification {
+            tolerance: default_tolerance(),
+            retries: default_retries(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+/// How a random selection of the music is synced
+pub struct Rotation {
+    /// How many days each selection lasts
+    #[serde(default = "default_period_days")]
+    pub period_days: u64,
+    /// Whether whole `album` folders or single `track`s are chosen
+    #[serde(default)]
+    pub by: Sample,
+    /// Whether higher rated files are more likely to be chosen
+    #[serde(default)]
+    pub weighted: bool,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+/// What a sync does if the files it would write don't fit in the destination's free space
+pub enum SpaceCheck {
+    /// Stop before writing anything
+    Refuse,
+    /// Log a warning, and sync until the destination is full
+    Warn,
+    /// Don't check
+    Off,
+}
+
+impl Default for SpaceCheck {
+    fn default() -> SpaceCheck {
+        SpaceCheck::Refuse
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+/// How synced playlists are written to the destination
+pub struct PlaylistExport {
+    /// A folder, relative to `dest_folder`, to put all playlists in.  By default each playlist
+    /// keeps its location relative to `source_folder`.
+    pub folder: Option<String>,
+    /// The separator between folders in playlist entries, i.e. `\` for some car stereos
+    #[serde(default = "default_path_separator")]
+    pub path_separator: String,
+    /// The text encoding of the written playlists
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+/// Which playlists are generated in the destination after syncing
+pub struct GeneratePlaylists {
+    /// The folder, relative to `dest_folder`, to put generated playlists in
+    #[serde(default = "default_playlist_folder")]
+    pub folder: String,
+    /// Whether to write a playlist for each album folder
+    #[serde(default)]
+    pub albums: bool,
+    /// Whether to write a playlist for each top-level artist folder
+    #[serde(default)]
+    pub artists: bool,
+    /// If set, write a playlist of the files whose source changed in this many days
+    pub recently_added_days: Option<u64>,
+    /// The separator between folders in playlist entries
+    #[serde(default = "default_path_separator")]
+    pub path_separator: String,
+    /// The text encoding of the written playlists
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+fn default_tolerance() -> f64 {
+    1.0
+}
+
+fn default_retries() -> u32 {
+    1
+}
+
+fn default_period_days() -> u64 {
+    7
+}
+
+fn default_reconvert_on_change() -> bool {
+    true
+}
+
+fn default_log_stderr() -> bool {
+    true
+}
+
+fn default_path_separator() -> String {
+    "/".to_owned()
+}
+
+fn default_playlist_folder() -> String {
+    "Playlists".to_owned()
+}
 
 impl Config {
     /// Creates a config struct from the configuration file
@@ -78,17 +274,418 @@ impl Config {
 
         Ok(config)
     }
+
+    /// Checks every setting that can be checked before syncing, and returns all of the problems
+    /// found, rather than stopping at the first
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        {
+            let mut problem = |key: String, message: String| {
+                problems.push(Problem {
+                    key: key,
+                    message: message,
+                })
+            };
+
+            let source = Path::new(&self.source_folder);
+            if !source.is_dir() {
+                problem("source_folder".to_owned(), "is not a folder".to_owned());
+            }
+            if is_inside(Path::new(&self.dest_folder), source) {
+                problem(
+                    "dest_folder".to_owned(),
+                    "is inside source_folder, so synced files would be synced again".to_owned(),
+                );
+            }
+
+            if self.files.is_empty() && self.playlists.as_ref().map_or(true, |p| p.is_empty()) {
+                problem(
+                    "files".to_owned(),
+                    "is empty, and there are no playlists, so there is nothing to sync".to_owned(),
+                );
+            }
+            let pattern_keys = [
+                ("files", Some(&self.files)),
+                ("playlists", self.playlists.as_ref()),
+            ];
+            for &(key, patterns) in &pattern_keys {
+                for (i, pattern) in patterns.into_iter().flat_map(|p| p).enumerate() {
+                    if let Err(e) = Pattern::new(&format!("{}/{}", self.source_folder, pattern)) {
+                        problem(format!("{}[{}]", key, i), format!("is not a valid glob: {}", e));
+                    }
+                }
+            }
+
+            for (i, regex) in self.exclude.iter().flat_map(|e| e).enumerate() {
+                if let Err(e) = Regex::new(regex) {
+                    problem(format!("exclude[{}]", i), format!("is not a valid regex: {}", e));
+                }
+            }
+            for (i, glob) in self.exclude_globs.iter().flat_map(|e| e).enumerate() {
+                if let Err(e) = Pattern::new(glob) {
+                    problem(
+                        format!("exclude_globs[{}]", i),
+                        format!("is not a valid glob: {}", e),
+                    );
+                }
+            }
+            for (i, rule) in self.exclude_tags.iter().flat_map(|e| e).enumerate() {
+                if let Err(e) = Predicate::parse(rule) {
+                    let causes: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+                    problem(format!("exclude_tags[{}]", i), causes.join(": "));
+                }
+            }
+
+            if let Err(e) = logger::level(&self.log_level, 0) {
+                problem("log_level".to_owned(), e.to_string());
+            }
+            if let Some(ref log_file) = self.log_file {
+                let folder = Path::new(log_file).parent().unwrap_or_else(|| Path::new(""));
+                if folder != Path::new("") && !folder.is_dir() {
+                    problem("log_file".to_owned(), "is not in an existing folder".to_owned());
+                }
+            }
+            if !self.log_stderr && self.log_file.is_none() {
+                problem(
+                    "log_stderr".to_owned(),
+                    "is off, but there is no log_file to write messages to".to_owned(),
+                );
+            }
+
+            if let Some(ref cache_folder) = self.cache_folder {
+                let cache_folder = Path::new(cache_folder);
+                if cache_folder.exists() && !cache_folder.is_dir() {
+                    problem("cache_folder".to_owned(), "is not a folder".to_owned());
+                } else if is_inside(cache_folder, Path::new(&self.dest_folder)) {
+                    problem(
+                        "cache_folder".to_owned(),
+                        "is inside dest_folder, so cached files would be synced".to_owned(),
+                    );
+                }
+            }
+
+            if let Some(ref max_size) = self.max_size {
+                if let Err(e) = budget::parse_size(max_size) {
+                    problem("max_size".to_owned(), e.to_string());
+                }
+            }
+            for (i, priority) in self.priority.iter().flat_map(|p| p).enumerate() {
+                match Priority::parse(priority) {
+                    Ok(Priority::Plays) if self.stats_file.is_none() => {
+                        problem(format!("priority[{}]", i), "plays needs a stats_file".to_owned())
+                    }
+                    Ok(_) => {}
+                    Err(e) => problem(format!("priority[{}]", i), e.to_string()),
+                }
+            }
+            if let Some(ref rotation) = self.rotation {
+                if self.max_size.is_none() {
+                    problem("rotation".to_owned(), "needs a max_size to fill".to_owned());
+                }
+                if rotation.period_days == 0 {
+                    problem("rotation.period_days".to_owned(), "must be more than 0".to_owned());
+                }
+            }
+            if let Some(ref verification) = self.verification {
+                if verification.tolerance < 0.0 || verification.tolerance.is_nan() {
+                    problem(
+                        "verification.tolerance".to_owned(),
+                        "must not be negative".to_owned(),
+                    );
+                }
+            }
+            if let Some(ref stats_file) = self.stats_file {
+                if !Path::new(stats_file).is_file() {
+                    problem("stats_file".to_owned(), "is not a file".to_owned());
+                }
+            }
+
+            if let Err(e) = support::check_target(&self.convert_profile.target_format) {
+                problem("convert_profile.target_format".to_owned(), e.to_string());
+            }
+            for (i, format) in self.convert_profile.acceptable_formats.iter().enumerate() {
+                if format != "quality:lossless" && format != "quality:lossy" &&
+                    ::find_codec(format).is_none()
+                {
+                    problem(
+                        format!("convert_profile.acceptable_formats[{}]", i),
+                        format!(
+                            "{} is not a supported codec, quality:lossless or quality:lossy",
+                            format
+                        ),
+                    );
+                }
+            }
+            if self.convert_profile.bit_rate == 0 {
+                problem(
+                    "convert_profile.bit_rate".to_owned(),
+                    "must be more than 0".to_owned(),
+                );
+            }
+
+            if let Some(ref export) = self.playlist_export {
+                if export.path_separator.is_empty() {
+                    problem(
+                        "playlist_export.path_separator".to_owned(),
+                        "is empty".to_owned(),
+                    );
+                }
+                if export.folder.as_ref().map_or(false, |f| Path::new(f).is_absolute()) {
+                    problem(
+                        "playlist_export.folder".to_owned(),
+                        "must be relative to dest_folder".to_owned(),
+                    );
+                }
+            }
+            if let Some(ref generate) = self.generate_playlists {
+                if generate.path_separator.is_empty() {
+                    problem(
+                        "generate_playlists.path_separator".to_owned(),
+                        "is empty".to_owned(),
+                    );
+                }
+                if Path::new(&generate.folder).is_absolute() {
+                    problem(
+                        "generate_playlists.folder".to_owned(),
+                        "must be relative to dest_folder".to_owned(),
+                    );
+                }
+            }
+        }
+        problems
+    }
+}
+
+#[derive(Debug, PartialEq)]
+/// Something wrong with a config setting
+pub struct Problem {
+    /// The setting, i.e. `convert_profile.target_format` or `files[2]`
+    pub key: String,
+    /// What is wrong with it
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// Checks whether `path` is `folder` or somewhere inside it.  Both are made absolute and
+/// resolved as far as they exist, so symlinks and `..` can't hide the overlap.
+///
+/// # Arguments
+///
+/// * `path` - The path that may be inside `folder`
+/// * `folder` - The folder to look in
+fn is_inside(path: &Path, folder: &Path) -> bool {
+    absolute(path).starts_with(absolute(folder))
+}
+
+/// Makes a path absolute, resolving symlinks in the part of it that exists and `..` in the rest
+///
+/// # Arguments
+///
+/// * `path` - The path, which may not exist
+fn absolute(path: &Path) -> PathBuf {
+    let path = env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(
+        |_| path.to_path_buf(),
+    );
+
+    let mut existing = path.as_path();
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+
+    let mut absolute = existing.canonicalize().unwrap_or_else(
+        |_| existing.to_path_buf(),
+    );
+    for name in rest.into_iter().rev() {
+        absolute.push(name);
+    }
+
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component.as_os_str()),
+        }
+    }
+    normalized
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Config, ConvertProfile};
+    use budget::Sample;
+    use sniff::Detection;
+    use super::{Config, ConvertProfile, Rotation, SpaceCheck, is_inside};
+    use std::path::Path;
+
+    fn test_config() -> Config {
+        Config {
+            source_folder: "test-files".to_owned(),
+            dest_folder: "/tmp/casm-test-dest".to_owned(),
+            exclude: None,
+            exclude_globs: None,
+            exclude_tags: None,
+            files: vec!["folder1".to_owned()],
+            playlists: None,
+            detection: Detection::Fast,
+            log_level: None,
+            log_file: None,
+            log_stderr: true,
+            cache_folder: None,
+            max_size: None,
+            priority: None,
+            stats_file: None,
+            space_check: SpaceCheck::Refuse,
+            rotation: None,
+            verification: None,
+            convert_profile: ConvertProfile {
+                target_format: "FLAC".to_owned(),
+                acceptable_formats: vec!["quality:lossy".to_owned()],
+                bit_rate: 320,
+                reconvert_on_change: true,
+            },
+            playlist_export: None,
+            generate_playlists: None,
+        }
+    }
+
+    fn problem_keys(config: &Config) -> Vec<String> {
+        config.validate().into_iter().map(|p| p.key).collect()
+    }
 
     #[test]
     fn invalid_config_path_err() {
         assert!(Config::new(Some("/tmp/does_not_exist")).is_err())
     }
 
+    #[test]
+    fn test_is_inside() {
+        assert!(is_inside(Path::new("test-files/out"), Path::new("test-files")));
+        assert!(is_inside(Path::new("test-files/folder1/../new"), Path::new("test-files")));
+        assert!(!is_inside(Path::new("test-files/../elsewhere"), Path::new("test-files")));
+    }
+
+    #[test]
+    fn test_validate_paths() {
+        let mut config = test_config();
+        config.source_folder = "test-files/not_a_folder".to_owned();
+        config.dest_folder = "test-files/not_a_folder/out".to_owned();
+        assert_eq!(problem_keys(&config), vec!["source_folder", "dest_folder"]);
+    }
+
+    #[test]
+    fn test_validate_patterns() {
+        let mut config = test_config();
+        config.files = vec!["folder1".to_owned(), "[".to_owned()];
+        config.exclude = Some(vec!["(".to_owned()]);
+        config.exclude_globs = Some(vec!["ok/*".to_owned(), "***".to_owned()]);
+        config.exclude_tags = Some(vec!["genre".to_owned()]);
+        assert_eq!(
+            problem_keys(&config),
+            vec!["files[1]", "exclude[0]", "exclude_globs[1]", "exclude_tags[0]"]
+        );
+    }
+
+    #[test]
+    fn test_validate_profile() {
+        let mut config = test_config();
+        config.convert_profile.target_format = "NOT_A_CODEC".to_owned();
+        config.convert_profile.acceptable_formats = vec!["MP3".to_owned(), "quality:ok".to_owned()];
+        config.convert_profile.bit_rate = 0;
+        assert_eq!(
+            problem_keys(&config),
+            vec![
+                "convert_profile.target_format",
+                "convert_profile.acceptable_formats[1]",
+                "convert_profile.bit_rate",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_logging() {
+        let mut config = test_config();
+        config.log_level = Some("loud".to_owned());
+        config.log_file = Some("test-files/not_a_folder/casm.log".to_owned());
+        assert_eq!(problem_keys(&config), vec!["log_level", "log_file"]);
+
+        config.log_level = Some("debug".to_owned());
+        config.log_file = None;
+        config.log_stderr = false;
+        assert_eq!(problem_keys(&config), vec!["log_stderr"]);
+
+        config.log_file = Some("casm.log".to_owned());
+        assert!(problem_keys(&config).is_empty());
+    }
+
+    #[test]
+    fn test_validate_budget() {
+        let mut config = test_config();
+        config.max_size = Some("lots".to_owned());
+        config.priority = Some(vec!["order".to_owned(), "plays".to_owned(), "size".to_owned()]);
+        assert_eq!(problem_keys(&config), vec!["max_size", "priority[1]", "priority[2]"]);
+
+        config.max_size = Some("64 GB".to_owned());
+        config.priority = Some(vec!["order".to_owned(), "plays".to_owned()]);
+        config.stats_file = Some("test-files/crocodiles.m3u8".to_owned());
+        assert!(problem_keys(&config).is_empty());
+    }
+
+    #[test]
+    fn test_validate_rotation() {
+        let mut config = test_config();
+        config.rotation = Some(Rotation {
+            period_days: 0,
+            by: Sample::Album,
+            weighted: false,
+        });
+        assert_eq!(problem_keys(&config), vec!["rotation", "rotation.period_days"]);
+
+        config.max_size = Some("8GB".to_owned());
+        config.rotation.as_mut().unwrap().period_days = 7;
+        assert!(problem_keys(&config).is_empty());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut config = test_config();
+        let fingerprint = config.convert_profile.fingerprint();
+        assert_eq!(fingerprint, "codec=FLAC;bit_rate=320;filter=anull;tags=copy");
+
+        config.convert_profile.acceptable_formats = vec!["quality:lossless".to_owned()];
+        assert_eq!(config.convert_profile.fingerprint(), fingerprint);
+        config.convert_profile.bit_rate = 128;
+        assert!(config.convert_profile.fingerprint() != fingerprint);
+    }
+
+    #[test]
+    fn test_validate_cache_folder() {
+        let mut config = test_config();
+        config.cache_folder = Some(
+            "test-files/folder1/How Doth The Little Crocodile.mp3".to_owned(),
+        );
+        assert_eq!(problem_keys(&config), vec!["cache_folder"]);
+
+        config.cache_folder = Some(format!("{}/.cache", config.dest_folder));
+        assert_eq!(problem_keys(&config), vec!["cache_folder"]);
+
+        config.cache_folder = Some("/tmp/casm-cache".to_owned());
+        assert!(problem_keys(&config).is_empty());
+    }
+
     #[test]
     fn valid_config_path() {
         let correct_config = Config {
@@ -98,12 +695,29 @@ mod tests {
                 r".*[Ii]nstrument(al)?( ver(.?|sion))?(\)|-|>)?\.[a-zA-Z0-9]+$".to_owned(),
                 r".*[O|o]ff-?[V|v]ocal.*".to_owned(),
             ]),
+            exclude_globs: None,
+            exclude_tags: None,
             files: vec!["BLACKPINK".to_owned(), "MAMAMOO".to_owned()],
+            playlists: None,
+            detection: Detection::Fast,
+            log_level: None,
+            log_file: None,
+            log_stderr: true,
+            cache_folder: None,
+            max_size: None,
+            priority: None,
+            stats_file: None,
+            space_check: SpaceCheck::Refuse,
+            rotation: None,
+            verification: None,
             convert_profile: ConvertProfile {
                 target_format: "OPUS".to_owned(),
                 acceptable_formats: vec!["quality:lossy".to_owned()],
                 bit_rate: 320,
+                reconvert_on_change: true,
             },
+            playlist_export: None,
+            generate_playlists: None,
         };
         assert_eq!(
             Config::new(Some("config.example.toml")).unwrap(),
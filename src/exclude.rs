@@ -0,0 +1,337 @@
+pub use errors::*;
+
+use glob::Pattern;
+use musicfile::Probe;
+use regex::{Regex, RegexSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+/// All of the user's rules for leaving files out of the sync.  Path rules are checked while
+/// scanning, and tag rules once the file has been probed.
+pub struct Exclude {
+    /// The folder that `globs` are relative to
+    root: PathBuf,
+    /// The `exclude` regexes, kept alongside the compiled set so a match can be reported
+    regexes: Vec<String>,
+    regex_set: Option<RegexSet>,
+    /// The `exclude_globs` patterns
+    globs: Vec<Pattern>,
+    /// The `exclude_tags` predicates
+    predicates: Vec<Predicate>,
+}
+
+impl Exclude {
+    /// Compiles the exclusion rules from the config
+    ///
+    /// # Arguments
+    ///
+    /// * `source_folder` - The folder that `exclude_globs` are relative to
+    /// * `exclude` - Regexes matched against the whole path
+    /// * `exclude_globs` - Glob patterns matched against the path relative to `source_folder`
+    /// * `exclude_tags` - Metadata rules, checked once the file has been probed
+    pub fn new(
+        source_folder: &str,
+        exclude: &Option<Vec<String>>,
+        exclude_globs: &Option<Vec<String>>,
+        exclude_tags: &Option<Vec<String>>,
+    ) -> Result<Exclude> {
+        let regexes = exclude.clone().unwrap_or_default();
+        let regex_set = if regexes.is_empty() {
+            None
+        } else {
+            Some(RegexSet::new(&regexes).chain_err(
+                || "Config exclude is not valid regex",
+            )?)
+        };
+
+        let mut globs = Vec::new();
+        for glob in exclude_globs.iter().flat_map(|globs| globs.iter()) {
+            globs.push(Pattern::new(glob).chain_err(|| {
+                format!("Config exclude_globs has an invalid pattern: {}", glob)
+            })?);
+        }
+
+        let mut predicates = Vec::new();
+        for predicate in exclude_tags.iter().flat_map(|tags| tags.iter()) {
+            predicates.push(Predicate::parse(predicate).chain_err(|| {
+                format!("Config exclude_tags has an invalid rule: {}", predicate)
+            })?);
+        }
+
+        Ok(Exclude {
+            root: PathBuf::from(source_folder),
+            regexes: regexes,
+            regex_set: regex_set,
+            globs: globs,
+            predicates: predicates,
+        })
+    }
+
+    /// Returns the rule that excludes the path, if any.  Regexes are matched against the whole
+    /// path and globs against the path relative to the source folder.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the music file
+    pub fn matches_path(&self, path: &Path) -> Option<String> {
+        if let Some(ref regex_set) = self.regex_set {
+            if let Some(i) = regex_set
+                .matches(path.to_str().unwrap_or(""))
+                .into_iter()
+                .next()
+            {
+                return Some(format!("exclude: {}", self.regexes[i]));
+            }
+        }
+
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        self.globs
+            .iter()
+            .find(|glob| glob.matches_path(relative))
+            .map(|glob| format!("exclude_globs: {}", glob.as_str()))
+    }
+
+    /// Returns the tag rule that excludes the probed file, if any
+    ///
+    /// # Arguments
+    ///
+    /// * `probe` - The stream information and tags of the music file
+    pub fn matches_probe(&self, probe: &Probe) -> Option<String> {
+        self.predicates
+            .iter()
+            .find(|predicate| predicate.matches(probe))
+            .map(|predicate| format!("exclude_tags: {}", predicate.source))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+/// A comparison operator in a tag rule
+enum Op {
+    Equal,
+    NotEqual,
+    Match,
+    NotMatch,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+#[derive(Debug)]
+/// A metadata rule, i.e. `genre = "Audiobook"`, `title ~ "(Instrumental)"` or `duration > 20m`
+pub struct Predicate {
+    /// The rule as the user wrote it
+    source: String,
+    /// The lowercased tag name, or `duration`
+    field: String,
+    op: Op,
+    /// The unquoted right-hand side
+    value: String,
+    /// The right-hand side as a regex, for `~` and `!~`
+    regex: Option<Regex>,
+}
+
+impl Predicate {
+    /// Parses a rule of the form `field op value`.  `op` is one of `=`, `!=`, `~` (regex),
+    /// `!~`, `>`, `>=`, `<` or `<=`, and `value` may be quoted.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The rule as written in the config
+    pub fn parse(source: &str) -> Result<Predicate> {
+        let syntax = Regex::new(r"^\s*([^\s!=~<>]+)\s*(!=|!~|>=|<=|=|~|>|<)\s*(.*?)\s*$").unwrap();
+        let captures = syntax.captures(source).ok_or(
+            "Expected a rule like `field = value`",
+        )?;
+
+        let op = match &captures[2] {
+            "=" => Op::Equal,
+            "!=" => Op::NotEqual,
+            "~" => Op::Match,
+            "!~" => Op::NotMatch,
+            ">" => Op::Greater,
+            ">=" => Op::GreaterEqual,
+            "<" => Op::Less,
+            _ => Op::LessEqual,
+        };
+
+        let mut value = &captures[3];
+        if value.len() >= 2 &&
+            ((value.starts_with('"') && value.ends_with('"')) ||
+                 (value.starts_with('\'') && value.ends_with('\'')))
+        {
+            value = &value[1..value.len() - 1];
+        }
+
+        let field = captures[1].to_lowercase();
+        let regex = match op {
+            Op::Match | Op::NotMatch => {
+                Some(Regex::new(value).chain_err(|| "Invalid regex")?)
+            }
+            _ => None,
+        };
+        if field == "duration" && regex.is_none() && parse_duration(value).is_none() {
+            bail!("Invalid duration: {}", value);
+        }
+
+        Ok(Predicate {
+            source: source.to_owned(),
+            field: field,
+            op: op,
+            value: value.to_owned(),
+            regex: regex,
+        })
+    }
+
+    /// Checks the rule against a probed file.  A missing tag never matches.
+    ///
+    /// # Arguments
+    ///
+    /// * `probe` - The stream information and tags of the music file
+    fn matches(&self, probe: &Probe) -> bool {
+        let (actual, number) = if self.field == "duration" {
+            match probe.duration {
+                Some(duration) => {
+                    let seconds = duration.as_secs();
+                    (seconds.to_string(), Some(seconds as f64))
+                }
+                None => return false,
+            }
+        } else {
+            match probe.tags.get(&self.field) {
+                Some(tag) => (tag.clone(), tag.trim().parse::<f64>().ok()),
+                None => return false,
+            }
+        };
+
+        let expected = if self.field == "duration" {
+            parse_duration(&self.value).map(|d| d as f64)
+        } else {
+            self.value.trim().parse::<f64>().ok()
+        };
+
+        match self.op {
+            Op::Match => self.regex.as_ref().map_or(false, |r| r.is_match(&actual)),
+            Op::NotMatch => self.regex.as_ref().map_or(false, |r| !r.is_match(&actual)),
+            Op::Equal | Op::NotEqual => {
+                let equal = match (number, expected) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => actual.to_lowercase() == self.value.to_lowercase(),
+                };
+                equal == (self.op == Op::Equal)
+            }
+            _ => {
+                match (number, expected) {
+                    (Some(a), Some(b)) => {
+                        match self.op {
+                            Op::Greater => a > b,
+                            Op::GreaterEqual => a >= b,
+                            Op::Less => a < b,
+                            _ => a <= b,
+                        }
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+/// Parses a duration like `90`, `90s`, `20m` or `1h30m` into seconds
+///
+/// # Arguments
+///
+/// * `duration` - The duration as written by the user
+fn parse_duration(duration: &str) -> Option<u64> {
+    let duration = duration.trim();
+    if let Ok(seconds) = duration.parse::<u64>() {
+        return Some(seconds);
+    }
+
+    let mut total = 0;
+    let mut number = String::new();
+    for c in duration.chars() {
+        if c.is_digit(10) {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        match number.parse::<u64>() {
+            Ok(n) => total += n * multiplier,
+            Err(_) => return None,
+        }
+        number.clear();
+    }
+
+    if number.is_empty() { Some(total) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Predicate, parse_duration};
+    use ffmpeg;
+    use musicfile::Probe;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn probe() -> Probe {
+        let mut tags = HashMap::new();
+        tags.insert("genre".to_owned(), "Audiobook".to_owned());
+        tags.insert("title".to_owned(), "Whistle (Instrumental)".to_owned());
+        tags.insert("date".to_owned(), "1987".to_owned());
+        Probe {
+            container: "mp3".to_owned(),
+            codec: ffmpeg::codec::Id::MP3,
+            bit_rate: Some(320_000),
+            sample_rate: 44100,
+            channels: 2,
+            duration: Some(Duration::from_secs(25 * 60)),
+            tags: tags,
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("20m"), Some(1200));
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+        assert_eq!(parse_duration("1h30"), None);
+        assert_eq!(parse_duration("soon"), None);
+    }
+
+    #[test]
+    fn test_tag_equal() {
+        assert!(Predicate::parse(r#"genre = "audiobook""#).unwrap().matches(&probe()));
+        assert!(!Predicate::parse("genre != Audiobook").unwrap().matches(&probe()));
+    }
+
+    #[test]
+    fn test_tag_regex() {
+        let predicate = Predicate::parse(r#"title ~ "\(Instrumental\)""#).unwrap();
+        assert!(predicate.matches(&probe()));
+    }
+
+    #[test]
+    fn test_numeric_comparison() {
+        assert!(Predicate::parse("duration > 20m").unwrap().matches(&probe()));
+        assert!(!Predicate::parse("duration <= 20m").unwrap().matches(&probe()));
+        assert!(Predicate::parse("date < 1990").unwrap().matches(&probe()));
+    }
+
+    #[test]
+    fn test_missing_tag() {
+        assert!(!Predicate::parse("artist = Nobody").unwrap().matches(&probe()));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(Predicate::parse("genre").is_err());
+        assert!(Predicate::parse("duration > forever").is_err());
+        assert!(Predicate::parse("title ~ (").is_err());
+    }
+}
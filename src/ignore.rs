@@ -0,0 +1,302 @@
+use glob::{MatchOptions, Pattern};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// The name of the per-directory ignore file
+pub const IGNORE_FILENAME: &'static str = ".casmignore";
+
+/// `*` and `?` should never cross a directory boundary, just like in gitignore
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Debug)]
+/// A single line of a `.casmignore` file
+struct Rule {
+    /// The glob to match against
+    pattern: Pattern,
+    /// Whether the rule re-includes a path that was excluded by an earlier rule (`!pattern`)
+    negated: bool,
+    /// Whether the rule only applies to directories (`pattern/`)
+    dir_only: bool,
+    /// Whether the rule is relative to the `.casmignore` location, or may match a file name at
+    /// any depth below it
+    anchored: bool,
+}
+
+impl Rule {
+    /// Parses a line of a `.casmignore` file, returning `None` for blanks and comments
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - A line of a `.casmignore` file
+    fn parse(line: &str) -> Option<Rule> {
+        let mut line = line.trim_right();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let negated = line.starts_with('!');
+        if negated {
+            line = &line[1..];
+        } else if line.starts_with("\\!") || line.starts_with("\\#") {
+            line = &line[1..];
+        }
+
+        let dir_only = line.ends_with('/');
+        if dir_only {
+            line = &line[..line.len() - 1];
+        }
+
+        let anchored = line.contains('/');
+        let line = line.trim_left_matches('/');
+        if line.is_empty() {
+            return None;
+        }
+
+        Pattern::new(line).ok().map(|pattern| {
+            Rule {
+                pattern: pattern,
+                negated: negated,
+                dir_only: dir_only,
+                anchored: anchored,
+            }
+        })
+    }
+
+    /// Checks whether the rule applies to a path
+    ///
+    /// # Arguments
+    ///
+    /// * `relative` - The path, relative to the directory holding the `.casmignore`
+    /// * `is_dir` - Whether the path is a directory
+    fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.pattern.matches_path_with(relative, &MATCH_OPTIONS)
+        } else {
+            match relative.file_name() {
+                Some(name) => self.pattern.matches_path_with(Path::new(name), &MATCH_OPTIONS),
+                None => false,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// The parsed contents of a single `.casmignore` file
+struct IgnoreFile {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreFile {
+    /// Parses the rules out of the contents of a `.casmignore` file
+    ///
+    /// # Arguments
+    ///
+    /// * `contents` - The text of the file
+    fn parse(contents: &str) -> IgnoreFile {
+        IgnoreFile { rules: contents.lines().filter_map(Rule::parse).collect() }
+    }
+
+    /// Returns `Some(true)` if the last rule matching the path excludes it, `Some(false)` if it
+    /// re-includes it, or `None` if no rule mentions the path at all.
+    ///
+    /// # Arguments
+    ///
+    /// * `relative` - The path, relative to the directory holding the `.casmignore`
+    /// * `is_dir` - Whether the path is a directory
+    fn is_ignored(&self, relative: &Path, is_dir: bool) -> Option<bool> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(relative, is_dir))
+            .map(|rule| !rule.negated)
+    }
+}
+
+/// Answers whether paths under a root directory are excluded by gitignore-style `.casmignore`
+/// files.  Rules in deeper directories override the ones above them, and nothing inside an
+/// ignored directory can be re-included.
+pub struct Ignore {
+    /// The directory above which `.casmignore` files are not read
+    root: PathBuf,
+    /// Parsed ignore files by directory, or `None` if the directory has no ignore file
+    files: HashMap<PathBuf, Option<IgnoreFile>>,
+    /// Paths whose status has already been worked out
+    known: HashMap<PathBuf, bool>,
+}
+
+impl Ignore {
+    /// Creates a new matcher for the tree below `root`
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The directory whose `.casmignore` files apply to everything under it
+    pub fn new<P: AsRef<Path>>(root: P) -> Ignore {
+        Ignore {
+            root: root.as_ref().to_path_buf(),
+            files: HashMap::new(),
+            known: HashMap::new(),
+        }
+    }
+
+    /// Checks whether a path, or any of the directories between the root and it, are ignored.
+    /// Paths outside of the root are never ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file or directory to check
+    /// * `is_dir` - Whether `path` is a directory
+    pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        let components: Vec<PathBuf> = match path.strip_prefix(&self.root) {
+            Ok(relative) => {
+                relative
+                    .components()
+                    .map(|c| PathBuf::from(c.as_os_str()))
+                    .collect()
+            }
+            Err(_) => return false,
+        };
+
+        let mut current = self.root.clone();
+        for (i, component) in components.iter().enumerate() {
+            current.push(component);
+            let current_is_dir = is_dir || i + 1 < components.len();
+            if self.check(&current, current_is_dir) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Checks a single path against every `.casmignore` between the root and its parent
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file or directory to check, which must be under the root
+    /// * `is_dir` - Whether `path` is a directory
+    fn check(&mut self, path: &Path, is_dir: bool) -> bool {
+        if let Some(&ignored) = self.known.get(path) {
+            return ignored;
+        }
+
+        let mut ignored = false;
+        let mut dir = self.root.clone();
+        let parent = path.parent().unwrap_or(&self.root).to_path_buf();
+        let below: Vec<PathBuf> = parent
+            .strip_prefix(&self.root)
+            .map(|relative| {
+                relative
+                    .components()
+                    .map(|c| PathBuf::from(c.as_os_str()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut dirs = vec![dir.clone()];
+        for component in below {
+            dir.push(component);
+            dirs.push(dir.clone());
+        }
+
+        for dir in dirs {
+            let relative = match path.strip_prefix(&dir) {
+                Ok(relative) => relative.to_path_buf(),
+                Err(_) => continue,
+            };
+            if let Some(ref file) = *self.load(&dir) {
+                if let Some(result) = file.is_ignored(&relative, is_dir) {
+                    ignored = result;
+                }
+            }
+        }
+
+        self.known.insert(path.to_path_buf(), ignored);
+        ignored
+    }
+
+    /// Reads and caches the `.casmignore` in a directory, if there is one
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory that may contain a `.casmignore`
+    fn load(&mut self, dir: &Path) -> &Option<IgnoreFile> {
+        self.files.entry(dir.to_path_buf()).or_insert_with(|| {
+            let mut contents = String::new();
+            match File::open(dir.join(IGNORE_FILENAME)) {
+                Ok(mut file) => {
+                    if file.read_to_string(&mut contents).is_ok() {
+                        Some(IgnoreFile::parse(&contents))
+                    } else {
+                        None
+                    }
+                }
+                Err(_) => None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ignore, IgnoreFile};
+    use std::path::Path;
+
+    #[test]
+    fn test_basename_matches_any_depth() {
+        let file = IgnoreFile::parse("*Instrumental*\n");
+        let path = Path::new("Artist/Album/01 Song (Instrumental).flac");
+        assert_eq!(file.is_ignored(path, false), Some(true));
+    }
+
+    #[test]
+    fn test_comments_and_blanks() {
+        let file = IgnoreFile::parse("# a comment\n\n   \n");
+        assert!(file.rules.is_empty());
+    }
+
+    #[test]
+    fn test_negation() {
+        let file = IgnoreFile::parse("*.flac\n!Keep.flac\n");
+        assert_eq!(file.is_ignored(Path::new("Drop.flac"), false), Some(true));
+        assert_eq!(file.is_ignored(Path::new("Keep.flac"), false), Some(false));
+        assert_eq!(file.is_ignored(Path::new("Other.mp3"), false), None);
+    }
+
+    #[test]
+    fn test_dir_only() {
+        let file = IgnoreFile::parse("Scans/\n");
+        assert_eq!(file.is_ignored(Path::new("Album/Scans"), true), Some(true));
+        assert_eq!(file.is_ignored(Path::new("Album/Scans"), false), None);
+    }
+
+    #[test]
+    fn test_anchored() {
+        let file = IgnoreFile::parse("/Live\nBonus/*.mp3\n");
+        assert_eq!(file.is_ignored(Path::new("Live"), true), Some(true));
+        assert_eq!(file.is_ignored(Path::new("Album/Live"), true), None);
+        assert_eq!(file.is_ignored(Path::new("Bonus/Track.mp3"), false), Some(true));
+        assert_eq!(file.is_ignored(Path::new("Bonus/Disc/Track.mp3"), false), None);
+    }
+
+    #[test]
+    fn test_ignore_file_in_tree() {
+        let mut ignore = Ignore::new("test-files");
+        assert!(ignore.is_ignored(
+            Path::new("test-files/folder2/Synth Synth Short.mp3"),
+            false,
+        ));
+        assert!(!ignore.is_ignored(
+            Path::new("test-files/Synth Synth Short.mp3"),
+            false,
+        ));
+        assert!(!ignore.is_ignored(Path::new("/elsewhere/Synth Synth Short.mp3"), false));
+    }
+}
@@ -0,0 +1,267 @@
+extern crate serde_json;
+
+pub use errors::*;
+
+use musicfile::{Action, Musicfile, Probe};
+use observer::Observer;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+/// What happened to a file, as written in a record
+pub enum Outcome {
+    /// The file was copied as it is
+    Copied,
+    /// The file was converted to the target format
+    Transcoded,
+    /// The synced copy of a file that was moved in the source was moved to match
+    Moved,
+    /// The file was already in the destination, or was excluded
+    Skipped,
+    /// The file could not be planned, copied or converted
+    Failed,
+}
+
+#[derive(Serialize, Debug)]
+/// The record written for each file
+pub struct FileRecord {
+    /// Always `file`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// The source music file
+    pub source: PathBuf,
+    /// The file in the destination, if there is one
+    pub destination: Option<PathBuf>,
+    /// What happened to the file
+    pub action: Outcome,
+    /// Why a skipped file was skipped, i.e. `keep existing` or the exclude rule
+    pub reason: Option<String>,
+    /// The codec of the source, if it could be read
+    pub codec_in: Option<String>,
+    /// The codec of the destination, if it was written
+    pub codec_out: Option<String>,
+    /// The length of the track in seconds
+    pub duration: Option<f64>,
+    /// The time spent copying or converting in seconds
+    pub elapsed: f64,
+    /// The size of the source in bytes
+    pub source_size: Option<u64>,
+    /// The size of the destination in bytes
+    pub destination_size: Option<u64>,
+    /// The error and everything that caused it, outermost first
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+/// The record written once every file has been processed
+pub struct SummaryRecord {
+    /// Always `summary`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// The number of files copied
+    pub copied: usize,
+    /// The number of files converted
+    pub transcoded: usize,
+    /// The number of synced copies moved
+    pub moved: usize,
+    /// The number of files left alone
+    pub skipped: usize,
+    /// The number of files that failed
+    pub failed: usize,
+    /// The time spent syncing in seconds
+    pub elapsed: f64,
+}
+
+/// Writes a JSON Lines record to stdout for each file as it is done, followed by a summary
+/// record
+pub struct JsonLines {
+    target_format: String,
+    state: Mutex<State>,
+}
+
+/// What the observer has seen so far
+struct State {
+    began: Instant,
+    /// The codec and length of each planned file, from what planning read from it
+    probed: HashMap<PathBuf, (String, Option<Duration>)>,
+    started: HashMap<PathBuf, Instant>,
+    summary: SummaryRecord,
+}
+
+impl JsonLines {
+    /// Creates the observer
+    ///
+    /// # Arguments
+    ///
+    /// * `target_format` - The codec that transcoded files are converted to
+    pub fn new(target_format: &str) -> JsonLines {
+        JsonLines {
+            target_format: target_format.to_owned(),
+            state: Mutex::new(State {
+                began: Instant::now(),
+                probed: HashMap::new(),
+                started: HashMap::new(),
+                summary: SummaryRecord {
+                    kind: "summary",
+                    ..SummaryRecord::default()
+                },
+            }),
+        }
+    }
+
+    /// Builds and writes the record for a file
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The source music file
+    /// * `action` - What was done with it, if anything
+    /// * `error` - What went wrong, if anything
+    fn record(&self, file: &Musicfile, action: Option<&Action>, error: Option<&Error>) {
+        let mut state = self.state.lock().unwrap();
+        let probed = state.probed.remove(&file.filename);
+        let codec_in = probed.as_ref().map(|&(ref codec, _)| codec.clone());
+        let outcome = match (action, error) {
+            (_, Some(_)) => Outcome::Failed,
+            (Some(&Action::Copy(_)), None) => Outcome::Copied,
+            (Some(&Action::Transcode(_)), None) => Outcome::Transcoded,
+            (Some(&Action::Move(..)), None) => Outcome::Moved,
+            _ => Outcome::Skipped,
+        };
+        let codec_out = match outcome {
+            Outcome::Copied => codec_in.clone(),
+            Outcome::Transcoded => Some(self.target_format.to_uppercase()),
+            _ => None,
+        };
+        let reason = match action {
+            Some(&Action::Keep(_)) => Some("keep existing".to_owned()),
+            Some(&Action::Exclude(ref rule)) => Some(rule.clone()),
+            _ => None,
+        };
+        let destination = action.and_then(|a| a.dest()).map(Path::to_path_buf);
+
+        let elapsed = state.started.remove(&file.filename).map_or(0.0, |started| {
+            seconds(started.elapsed())
+        });
+        match outcome {
+            Outcome::Copied => state.summary.copied += 1,
+            Outcome::Transcoded => state.summary.transcoded += 1,
+            Outcome::Moved => state.summary.moved += 1,
+            Outcome::Skipped => state.summary.skipped += 1,
+            Outcome::Failed => state.summary.failed += 1,
+        }
+
+        let record = FileRecord {
+            kind: "file",
+            source: file.filename.clone(),
+            destination_size: destination.as_ref().and_then(|d| size(d)),
+            destination: destination,
+            action: outcome,
+            reason: reason,
+            codec_in: codec_in,
+            codec_out: codec_out,
+            duration: probed.and_then(|(_, duration)| duration).map(seconds),
+            elapsed: elapsed,
+            source_size: size(&file.filename),
+            errors: error.map_or(Vec::new(), |e| e.iter().map(|e| e.to_string()).collect()),
+        };
+        write_line(&record);
+    }
+}
+
+impl Observer for JsonLines {
+    fn begin(&self, _: usize) {
+        self.state.lock().unwrap().began = Instant::now();
+    }
+
+    fn planned(&self, file: &Musicfile, _: &Action, probe: Option<&Probe>) {
+        if let Some(probe) = probe {
+            self.state.lock().unwrap().probed.insert(
+                file.filename.clone(),
+                (probe.codec.name().to_owned(), probe.duration),
+            );
+        }
+    }
+
+    fn started(&self, file: &Musicfile, _: &Action) {
+        self.state.lock().unwrap().started.insert(
+            file.filename.clone(),
+            Instant::now(),
+        );
+    }
+
+    fn finished(&self, file: &Musicfile, action: &Action) {
+        self.record(file, Some(action), None);
+    }
+
+    fn skipped(&self, file: &Musicfile, action: &Action) {
+        self.record(file, Some(action), None);
+    }
+
+    fn failed(&self, file: &Musicfile, error: &Error) {
+        self.record(file, None, Some(error));
+    }
+
+    fn end(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.summary.elapsed = seconds(state.began.elapsed());
+        write_line(&state.summary);
+    }
+}
+
+/// Writes a record to stdout on a line of its own
+///
+/// # Arguments
+///
+/// * `record` - The record to write
+fn write_line<T: ::serde::Serialize>(record: &T) {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    // A record that can't be written is lost, as there is nowhere better to report it
+    if serde_json::to_writer(&mut stdout, record).is_ok() {
+        let _ = writeln!(stdout, "");
+    }
+}
+
+/// The size of a file in bytes, if it exists
+fn size(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok().map(|m| m.len())
+}
+
+/// A duration in fractional seconds
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Outcome, SummaryRecord, serde_json};
+
+    #[test]
+    fn test_outcome_names() {
+        assert_eq!(serde_json::to_string(&Outcome::Transcoded).unwrap(), "\"transcoded\"");
+        assert_eq!(serde_json::to_string(&Outcome::Failed).unwrap(), "\"failed\"");
+    }
+
+    #[test]
+    fn test_summary_record() {
+        let summary = SummaryRecord {
+            kind: "summary",
+            copied: 1,
+            transcoded: 2,
+            moved: 0,
+            skipped: 3,
+            failed: 0,
+            elapsed: 1.5,
+        };
+        assert_eq!(
+            serde_json::to_string(&summary).unwrap(),
+            "{\"type\":\"summary\",\"copied\":1,\"transcoded\":2,\"moved\":0,\"skipped\":3,\
+             \"failed\":0,\"elapsed\":1.5}"
+        );
+    }
+}
@@ -1,14 +1,22 @@
 #[macro_use]
 extern crate clap;
+extern crate ctrlc;
 #[macro_use]
 extern crate error_chain;
 extern crate ffmpeg;
-extern crate gag;
+extern crate fs2;
 extern crate glob;
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate notify;
 extern crate phf;
 extern crate pbr;
 extern crate regex;
 extern crate serde;
+extern crate sha2;
 extern crate scoped_threadpool;
 #[macro_use]
 extern crate serde_derive;
@@ -18,33 +26,74 @@ extern crate walkdir;
 extern crate xdg;
 
 use clap::App;
-use config::Config;
-use gag::Gag;
+use codec::Codec;
 use glob::glob;
-use musicfile::Musicfile;
-use pbr::ProgressBar;
-use regex::RegexSet;
-use scoped_threadpool::Pool;
+use ignore::Ignore;
+use log::LogLevelFilter;
+use playlist::Playlist;
 use std::collections::HashSet;
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::process;
 use unicase::UniCase;
 use walkdir::WalkDir;
 
 include!("codecs_generated.rs");
 
+mod autoplaylist;
+mod budget;
+mod cache;
+mod cancel;
 mod codec;
+mod commands;
 mod config;
+mod exclude;
+mod ignore;
+mod json;
+mod logger;
+mod manifest;
 mod musicfile;
+mod observer;
+mod playlist;
+mod sniff;
+mod summary;
+mod support;
+mod syncer;
 mod transcoder;
 
 #[allow(unknown_lints)]
 #[allow(unused_doc_comment)]
 pub mod errors {
-    error_chain!{}
+    error_chain!{
+        foreign_links {
+            Ffmpeg(::ffmpeg::Error);
+        }
+
+        errors {
+            Cancelled {
+                description("cancelled")
+                display("Cancelled before it finished")
+            }
+            NoSpace {
+                description("no space left")
+                display("There is no space left in the destination")
+            }
+        }
+    }
 }
 
+pub use budget::Sample;
+pub use cache::Cache;
+pub use cancel::Cancel;
+pub use config::{Config, ConvertProfile, GeneratePlaylists, PlaylistExport, Problem, Rotation,
+                 SpaceCheck, Verification};
 pub use errors::*;
+pub use exclude::Exclude;
+pub use musicfile::{Action, Musicfile, Probe};
+pub use observer::{Observer, Progress, Quiet};
+pub use playlist::Encoding;
+pub use sniff::Detection;
+pub use summary::Summary;
+pub use syncer::{Planned, Report, Syncer};
 
 /// Runs the program
 pub fn run() -> Result<()> {
@@ -53,146 +102,297 @@ pub fn run() -> Result<()> {
         .author(crate_authors!())
         .version(crate_version!())
         .get_matches();
-    let config = Config::new(matches.value_of("config")).chain_err(
+
+    // Global arguments may come before or after the subcommand
+    let (subcommand, args) = matches.subcommand();
+    let args = args.unwrap_or(&matches);
+    let verbose = args.occurrences_of("verbose");
+
+    if subcommand == "codecs" {
+        return commands::codecs();
+    }
+
+    let config = Config::new(args.value_of("config")).chain_err(
         || "Unable to read config",
     )?;
 
-    let verbose = matches.occurrences_of("verbose");
-
-    if verbose > 1 {
-        println!("Configuration:\n{:#?}", config);
+    // Checked before logging starts, so that a log_file that can't be opened is listed with
+    // every other problem
+    if subcommand == "config" {
+        return commands::check_config(&config);
     }
 
-    let exclude = match config.exclude {
-        Some(exclude_pattern) => {
-            Some(RegexSet::new(exclude_pattern).chain_err(
-                || "Config exclude is not valid regex",
-            )?)
-        }
-        _ => None,
-    };
+    logger::init(
+        logger::level(&config.log_level, verbose).unwrap_or(LogLevelFilter::Warn),
+        config.log_file.as_ref().map(Path::new),
+        config.log_stderr,
+    )?;
+    debug!("Configuration:\n{:#?}", config);
 
-    let files = scan_files(&config.source_folder, config.files, &exclude);
+    let syncer = Syncer::new(config)?;
 
-    if verbose > 2 {
-        println!("Files:\n{:#?}", files);
+    // The first interrupt lets the files in progress finish or clean up after themselves, and
+    // the second gives up on them, keeping the record of the files that have finished
+    let cancel = syncer.cancel_handle();
+    ctrlc::set_handler(move || if cancel.is_cancelled() {
+        cancel.save_progress();
+        process::exit(130);
+    } else {
+        warn!("Stopping; interrupt again to quit immediately");
+        cancel.cancel();
+    }).chain_err(|| "Unable to handle interrupts")?;
+    match subcommand {
+        "plan" => commands::plan(&syncer),
+        "probe" => {
+            let files: Vec<&str> = args.values_of("files").unwrap().collect();
+            commands::probe(&syncer, &files)
+        }
+        "clean" => commands::clean(&syncer, args.is_present("dry-run")),
+        "verify" => commands::verify(&syncer, args.is_present("quick")),
+        "watch" => commands::watch(&syncer),
+        _ => commands::sync(&syncer, args.value_of("output") == Some("json")),
     }
+}
 
-    process_files(
-        files,
-        &config.source_folder,
-        &config.dest_folder,
-        &config.convert_profile,
-    );
-
-    Ok(())
+/// Looks up a codec by its name in `ffmpeg::codec::id::Id`, ignoring case
+///
+/// # Arguments
+///
+/// * `name` - The name of the codec, i.e. `FLAC`
+fn find_codec(name: &str) -> Option<&'static Codec<'static>> {
+    // This transmute should be safe as `get` will not store the reference with
+    // the expanded lifetime. This is due to `Borrow` being overly strict and
+    // can't have an impl for `&'static str` to `Borrow<&'a str>`.
+    let key = unsafe { ::std::mem::transmute::<_, &'static str>(name) };
+    ALL_CODECS.get(&UniCase(key))
 }
 
-/// Creates a unique set of music files from the configuration data.  It expands globs and walks directories
+/// Creates a unique set of music files from the configuration data.  It expands globs, walks
+/// directories and reads playlists, skipping anything excluded by a `.casmignore` file under
+/// `prefix`.
 ///
 /// # Arguments
 ///
 /// * `prefix` - The name of the root directory in which files may be found
-/// * `files` - An vector of folder names and/or glob patterns
-/// * `exclude` - A regex to exclude
-fn scan_files(prefix: &str, files: Vec<String>, exclude: &Option<RegexSet>) -> HashSet<Musicfile> {
+/// * `files` - An vector of folder names, playlists and/or glob patterns
+/// * `exclude` - The rules to exclude
+/// * `detection` - How to tell whether a file is music
+fn scan_files(
+    prefix: &str,
+    files: Vec<String>,
+    exclude: &Exclude,
+    detection: Detection,
+) -> HashSet<Musicfile> {
     let mut musicfiles = HashSet::new();
+    let mut ignore = Ignore::new(prefix);
     for file in files {
         let file = prefix.to_owned() + "/" + &*file;
-        for entry in glob(&*file).expect("Failed to read glob pattern") {
+        let entries = match glob(&*file) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("{}: {}", file, e);
+                continue;
+            }
+        };
+        for entry in entries {
             match entry {
                 Ok(path) => {
-                    for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+                    if path.is_file() && playlist::Format::from_path(&path).is_some() {
+                        scan_playlist(
+                            &path,
+                            prefix,
+                            &mut ignore,
+                            exclude,
+                            detection,
+                            &mut musicfiles,
+                        );
+                        continue;
+                    }
+                    for entry in WalkDir::new(path)
+                        .into_iter()
+                        .filter_entry(|e| !ignore.is_ignored(e.path(), e.file_type().is_dir()))
+                        .filter_map(|e| e.ok())
+                    {
                         if let Some(musicfile) = Musicfile::new(
                             entry.path().to_path_buf(),
                             exclude,
+                            detection,
                         )
                         {
                             musicfiles.insert(musicfile);
                         }
                     }
                 }
-                Err(e) => println!("{:?}", e),
+                Err(e) => warn!("{}", e),
             }
         }
     }
     musicfiles
 }
 
-/// Runs the processing and copying on each file.  This is done in a multithreaded manner and shows
-/// a progressbar.
+/// Creates the set of music files at or under the given paths that `scan_files` would find, so
+/// that only what has changed is walked.  A changed playlist brings in all of its entries.
 ///
 /// # Arguments
 ///
-/// * `musicfiles` - The hashset to take music files from
-/// * `source_folder` - The folder to take music files from
-/// * `dest_folder` - The folder to put processed music files in
-/// * `convert_profile` - Conversion settings
-fn process_files(
-    musicfiles: HashSet<Musicfile>,
-    source_folder: &str,
-    dest_folder: &str,
-    convert_profile: &config::ConvertProfile,
-) {
-    let mut pool = Pool::new(4);
-
-
-    let mut pb = ProgressBar::new(musicfiles.len() as u64);
-    pb.tick_format("▏▎▍▌▋▊▉██▉▊▋▌▍▎▏");
-    pb.show_message = true;
-    let pb = Arc::new(Mutex::new(pb));
-
-    // ffmpeg likes writing things to stderr, but we have a progress bar
-    // later on, we'll redirect stuff to a log?
-    let _gag_stderr = Gag::stderr().ok();
-
-    pool.scoped(|scope| {
-        for file in musicfiles {
-            let pb = pb.clone();
-            scope.execute(move || {
-                pb.lock().unwrap().message(&format!(
-                    "Processing {}: ",
-                    file.filename.file_name().unwrap().to_str().unwrap_or(
-                        "invalid filename",
-                    )
-                ));
-                if let Err(ref e) = file.process_file(source_folder, dest_folder, convert_profile) {
-                    use std::io::Write;
-                    let stderr = &mut ::std::io::stderr();
-                    let errmsg = "Error writing to stderr";
-                    writeln!(
-                        stderr,
-                        "error processing {}: {}",
-                        file.filename.to_str().unwrap_or("invalid filename"),
-                        e
-                    ).expect(errmsg);
-
-                    for e in e.iter().skip(1) {
-                        writeln!(stderr, "\tcaused by: {}", e).expect(errmsg);
+/// * `prefix` - The name of the root directory in which files may be found
+/// * `files` - An vector of folder names, playlists and/or glob patterns
+/// * `changed` - The files and folders that have changed
+/// * `exclude` - The rules to exclude
+/// * `detection` - How to tell whether a file is music
+fn scan_changed_files(
+    prefix: &str,
+    files: Vec<String>,
+    changed: &[PathBuf],
+    exclude: &Exclude,
+    detection: Detection,
+) -> HashSet<Musicfile> {
+    // The folders and files that `scan_files` walks, and the files that playlists refer to
+    let mut roots = Vec::new();
+    let mut listed = HashSet::new();
+    let mut candidates = Vec::new();
+    for file in files {
+        let file = prefix.to_owned() + "/" + &*file;
+        let entries = match glob(&*file) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("{}: {}", file, e);
+                continue;
+            }
+        };
+        for path in entries.filter_map(|e| e.ok()) {
+            if !(path.is_file() && playlist::Format::from_path(&path).is_some()) {
+                roots.push(path);
+                continue;
+            }
+            let playlist = match Playlist::read(&path) {
+                Ok(playlist) => playlist,
+                Err(e) => {
+                    warn!("{}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let playlist_changed = changed.iter().any(|c| path.starts_with(c));
+            for entry in &playlist.entries {
+                if let Some(file) = playlist.resolve(entry, Path::new(prefix)) {
+                    if playlist_changed {
+                        candidates.push(file.clone());
                     }
+                    listed.insert(file);
+                }
+            }
+        }
+    }
 
-                    // The backtrace is not always generated. Try to run this example
-                    // with `RUST_BACKTRACE=1`.
-                    if let Some(backtrace) = e.backtrace() {
-                        writeln!(stderr, "backtrace: {:?}", backtrace).expect(errmsg);
-                    }
+    let mut ignore = Ignore::new(prefix);
+    for path in changed {
+        if !path.is_dir() {
+            candidates.push(path.clone());
+            continue;
+        }
+        for entry in WalkDir::new(path)
+            .into_iter()
+            .filter_entry(|e| !ignore.is_ignored(e.path(), e.file_type().is_dir()))
+            .filter_map(|e| e.ok())
+        {
+            candidates.push(entry.path().to_path_buf());
+        }
+    }
+
+    let mut musicfiles = HashSet::new();
+    for file in candidates {
+        let selected = listed.contains(&file) || roots.iter().any(|root| file.starts_with(root));
+        if !selected || !file.is_file() || ignore.is_ignored(&file, false) {
+            continue;
+        }
+        if let Some(musicfile) = Musicfile::new(file, exclude, detection) {
+            musicfiles.insert(musicfile);
+        }
+    }
+    musicfiles
+}
+
+/// Adds the music files that a playlist refers to, and reports the entries that can't be found
+///
+/// # Arguments
+///
+/// * `path` - The playlist file
+/// * `prefix` - The name of the root directory in which files may be found
+/// * `ignore` - The `.casmignore` rules under `prefix`
+/// * `exclude` - The rules to exclude
+/// * `detection` - How to tell whether a file is music
+/// * `musicfiles` - The set to add music files to
+fn scan_playlist(
+    path: &Path,
+    prefix: &str,
+    ignore: &mut Ignore,
+    exclude: &Exclude,
+    detection: Detection,
+    musicfiles: &mut HashSet<Musicfile>,
+) {
+    let playlist = match Playlist::read(path) {
+        Ok(playlist) => playlist,
+        Err(e) => {
+            warn!("{}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    for entry in &playlist.entries {
+        match playlist.resolve(entry, Path::new(prefix)) {
+            Some(file) => {
+                if ignore.is_ignored(&file, false) {
+                    continue;
+                }
+                if let Some(musicfile) = Musicfile::new(file, exclude, detection) {
+                    musicfiles.insert(musicfile);
+                }
+            }
+            None => warn!("{}: could not find {}", path.display(), entry),
+        }
+    }
+}
+
+/// Finds the playlists among the configured files and glob patterns
+///
+/// # Arguments
+///
+/// * `prefix` - The name of the root directory in which files may be found
+/// * `files` - An vector of folder names, playlists and/or glob patterns
+fn scan_playlists(prefix: &str, files: Vec<String>) -> Vec<Playlist> {
+    let mut playlists = Vec::new();
+    for file in files {
+        let file = prefix.to_owned() + "/" + &*file;
+        let entries = match glob(&*file) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("{}: {}", file, e);
+                continue;
+            }
+        };
+        for path in entries.filter_map(|e| e.ok()) {
+            if path.is_file() && playlist::Format::from_path(&path).is_some() {
+                match Playlist::read(&path) {
+                    Ok(playlist) => playlists.push(playlist),
+                    Err(e) => warn!("{}: {}", path.display(), e),
                 }
-                pb.lock().unwrap().inc();
-            });
+            }
         }
-    });
-    pb.lock().unwrap().finish_print("Sync Complete");
+    }
+    playlists
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
-    use super::{Musicfile, scan_files};
+    use exclude::Exclude;
+    use sniff::Detection;
+    use super::{Musicfile, find_codec, scan_changed_files, scan_files};
 
     #[test]
     fn test_scan_folder() {
         let files = vec!["folder1".to_owned()];
-        let musicfiles = scan_files("test-files", files, &None);
+        let musicfiles = scan_files("test-files", files, &Exclude::default(), Detection::Fast);
         let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
         let should_contain = Musicfile { filename: filename };
         assert_eq!(musicfiles.contains(&should_contain), true);
@@ -202,7 +402,7 @@ mod tests {
     #[test]
     fn test_scan_glob() {
         let files = vec!["folder*/*Crocodile*".to_owned()];
-        let musicfiles = scan_files("test-files", files, &None);
+        let musicfiles = scan_files("test-files", files, &Exclude::default(), Detection::Fast);
         let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
         let should_contain = Musicfile { filename: filename };
         assert_eq!(musicfiles.contains(&should_contain), true);
@@ -212,7 +412,7 @@ mod tests {
     #[test]
     fn test_scan_filename() {
         let files = vec!["/folder1/How Doth The Little Crocodile.mp3".to_owned()];
-        let musicfiles = scan_files("test-files/", files, &None);
+        let musicfiles = scan_files("test-files/", files, &Exclude::default(), Detection::Fast);
         let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
         let should_contain = Musicfile { filename: filename };
         assert_eq!(musicfiles.contains(&should_contain), true);
@@ -222,24 +422,44 @@ mod tests {
     #[test]
     fn test_scan_empty() {
         let files = vec!["folder1/*.txt".to_owned()];
-        let musicfiles = scan_files("test-files", files, &None);
+        let musicfiles = scan_files("test-files", files, &Exclude::default(), Detection::Fast);
         assert_eq!(musicfiles.is_empty(), true);
     }
 
     #[test]
     fn test_scan_nonexistant() {
         let files = vec!["not_a_folder".to_owned()];
-        let musicfiles = scan_files("test-files", files, &None);
+        let musicfiles = scan_files("test-files", files, &Exclude::default(), Detection::Fast);
         assert_eq!(musicfiles.is_empty(), true);
     }
 
     #[test]
     fn test_scan_text_file() {
         let files = vec!["folder2/notmusic.txt".to_owned()];
-        let musicfiles = scan_files("test-files", files, &None);
+        let musicfiles = scan_files("test-files", files, &Exclude::default(), Detection::Fast);
+        assert_eq!(musicfiles.is_empty(), true);
+    }
+
+    #[test]
+    fn test_scan_casmignore() {
+        let files = vec!["folder2".to_owned(), "folder2/Synth Synth Short.mp3".to_owned()];
+        let musicfiles = scan_files("test-files", files, &Exclude::default(), Detection::Fast);
         assert_eq!(musicfiles.is_empty(), true);
     }
 
+    #[test]
+    fn test_scan_playlist() {
+        let files = vec!["crocodiles.m3u8".to_owned()];
+        let musicfiles = scan_files("test-files", files, &Exclude::default(), Detection::Fast);
+        let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
+        let should_contain = Musicfile { filename: filename };
+        assert_eq!(musicfiles.contains(&should_contain), true);
+        let filename = PathBuf::from("test-files/Synth Synth Short.mp3");
+        let should_contain = Musicfile { filename: filename };
+        assert_eq!(musicfiles.contains(&should_contain), true);
+        assert_eq!(musicfiles.len(), 2);
+    }
+
     #[test]
     fn test_scan_duplicates() {
         let files = vec![
@@ -247,10 +467,50 @@ mod tests {
             "folder1/".to_owned(),
             "folder1/*".to_owned(),
         ];
-        let musicfiles = scan_files("test-files", files, &None);
+        let musicfiles = scan_files("test-files", files, &Exclude::default(), Detection::Fast);
         let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
         let should_contain = Musicfile { filename: filename };
         assert_eq!(musicfiles.contains(&should_contain), true);
         assert_eq!(musicfiles.len(), 1);
     }
+
+    #[test]
+    fn test_scan_changed() {
+        let files = vec!["folder1".to_owned(), "folder2".to_owned()];
+        let changed = vec![PathBuf::from("test-files/folder1")];
+        let musicfiles = scan_changed_files(
+            "test-files",
+            files.clone(),
+            &changed,
+            &Exclude::default(),
+            Detection::Fast,
+        );
+        let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
+        assert!(musicfiles.contains(&Musicfile { filename: filename }));
+        assert_eq!(musicfiles.len(), 1);
+
+        // Files that aren't in `files`, or are ignored, are left out even when they change
+        let changed = vec![
+            PathBuf::from("test-files/Synth Synth Short.mp3"),
+            PathBuf::from("test-files/folder2/Synth Synth Short.mp3"),
+        ];
+        let musicfiles =
+            scan_changed_files("test-files", files, &changed, &Exclude::default(), Detection::Fast);
+        assert!(musicfiles.is_empty());
+    }
+
+    #[test]
+    fn test_scan_changed_playlist() {
+        let files = vec!["crocodiles.m3u8".to_owned()];
+        let changed = vec![PathBuf::from("test-files/crocodiles.m3u8")];
+        let musicfiles =
+            scan_changed_files("test-files", files, &changed, &Exclude::default(), Detection::Fast);
+        assert_eq!(musicfiles.len(), 2);
+    }
+
+    #[test]
+    fn test_find_codec() {
+        assert_eq!(find_codec("flac").unwrap().name, "FLAC");
+        assert!(find_codec("NOT_A_CODEC").is_none());
+    }
 }
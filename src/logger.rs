@@ -0,0 +1,187 @@
+extern crate ffmpeg;
+
+pub use errors::*;
+
+use ffmpeg::ffi;
+use log::{self, LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+use observer;
+use std::cmp;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The levels that `-v` steps through, quietest first
+const LEVELS: [LogLevelFilter; 6] = [
+    LogLevelFilter::Off,
+    LogLevelFilter::Error,
+    LogLevelFilter::Warn,
+    LogLevelFilter::Info,
+    LogLevelFilter::Debug,
+    LogLevelFilter::Trace,
+];
+
+/// Writes log messages to stderr, above the progress bar if one is shown, and appends them to a
+/// log file if one is configured
+struct Logger {
+    level: LogLevelFilter,
+    stderr: bool,
+    file: Option<Mutex<File>>,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{}: {}: {}",
+            record.level().to_string().to_lowercase(),
+            record.target(),
+            record.args()
+        );
+        if self.stderr {
+            observer::write_above_bar(&line);
+        }
+        // There is nowhere to report a log message that can't be written
+        if let Some(ref file) = self.file {
+            let _ = writeln!(file.lock().unwrap(), "{}", line);
+        }
+    }
+}
+
+/// The level to log at, from the `log_level` setting raised by one step for each `-v`
+///
+/// # Arguments
+///
+/// * `log_level` - The `log_level` from the config, which defaults to `warn`
+/// * `verbose` - The number of times `-v` was given
+pub fn level(log_level: &Option<String>, verbose: u64) -> Result<LogLevelFilter> {
+    let base = match *log_level {
+        Some(ref name) => {
+            name.parse().map_err(|_| {
+                format!(
+                    "{} is not a log level; use off, error, warn, info, debug or trace",
+                    name
+                )
+            })?
+        }
+        None => LogLevelFilter::Warn,
+    };
+    let index = LEVELS.iter().position(|&l| l == base).unwrap_or(0) + verbose as usize;
+    Ok(LEVELS[cmp::min(index, LEVELS.len() - 1)])
+}
+
+/// Sets up logging for casm and for ffmpeg, whose messages would otherwise be written straight
+/// to stderr
+///
+/// # Arguments
+///
+/// * `level` - The most detailed level to log
+/// * `log_file` - A file to append messages to
+/// * `stderr` - Whether to show messages on stderr too
+pub fn init(level: LogLevelFilter, log_file: Option<&Path>, stderr: bool) -> Result<()> {
+    let file = match log_file {
+        Some(path) => {
+            Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .chain_err(|| format!("Unable to open log file {}", path.display()))?,
+            ))
+        }
+        None => None,
+    };
+
+    log::set_logger(|max_level| {
+        max_level.set(level);
+        Box::new(Logger {
+            level: level,
+            stderr: stderr,
+            file: file,
+        })
+    }).chain_err(|| "Unable to set up logging")?;
+
+    ffmpeg::init().chain_err(|| "Unable to initialize ffmpeg")?;
+    unsafe {
+        ffi::av_log_set_callback(Some(ffmpeg_log));
+    }
+    Ok(())
+}
+
+/// Maps an ffmpeg log level (`AV_LOG_*`) to a log level
+///
+/// # Arguments
+///
+/// * `level` - The ffmpeg log level
+fn ffmpeg_level(level: c_int) -> LogLevel {
+    if level <= ffi::AV_LOG_ERROR {
+        LogLevel::Error
+    } else if level <= ffi::AV_LOG_WARNING {
+        LogLevel::Warn
+    } else if level <= ffi::AV_LOG_INFO {
+        LogLevel::Info
+    } else if level <= ffi::AV_LOG_VERBOSE {
+        LogLevel::Debug
+    } else {
+        LogLevel::Trace
+    }
+}
+
+/// Receives ffmpeg's log messages in place of its default callback, which writes to stderr
+extern "C" fn ffmpeg_log(ptr: *mut c_void, level: c_int, fmt: *const c_char, vl: ffi::va_list) {
+    let log_level = ffmpeg_level(level);
+    if !log_enabled!(target: "ffmpeg", log_level) {
+        return;
+    }
+
+    let mut line = [0 as c_char; 1024];
+    let mut print_prefix = 1;
+    let message = unsafe {
+        ffi::av_log_format_line(
+            ptr,
+            level,
+            fmt,
+            vl,
+            line.as_mut_ptr(),
+            line.len() as c_int,
+            &mut print_prefix,
+        );
+        CStr::from_ptr(line.as_ptr()).to_string_lossy().into_owned()
+    };
+    let message = message.trim_right();
+    if !message.is_empty() {
+        log!(target: "ffmpeg", log_level, "{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg::ffi;
+    use log::{LogLevel, LogLevelFilter};
+    use super::{ffmpeg_level, level};
+
+    #[test]
+    fn test_level() {
+        assert_eq!(level(&None, 0).unwrap(), LogLevelFilter::Warn);
+        assert_eq!(level(&None, 2).unwrap(), LogLevelFilter::Debug);
+        assert_eq!(level(&Some("error".to_owned()), 1).unwrap(), LogLevelFilter::Warn);
+        assert_eq!(level(&Some("info".to_owned()), 9).unwrap(), LogLevelFilter::Trace);
+        assert!(level(&Some("loud".to_owned()), 0).is_err());
+    }
+
+    #[test]
+    fn test_ffmpeg_level() {
+        assert_eq!(ffmpeg_level(ffi::AV_LOG_FATAL), LogLevel::Error);
+        assert_eq!(ffmpeg_level(ffi::AV_LOG_WARNING), LogLevel::Warn);
+        assert_eq!(ffmpeg_level(ffi::AV_LOG_VERBOSE), LogLevel::Debug);
+        assert_eq!(ffmpeg_level(ffi::AV_LOG_DEBUG), LogLevel::Trace);
+    }
+}
@@ -0,0 +1,346 @@
+pub use errors::*;
+
+use cache;
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use toml;
+
+/// The name of the manifest file, which is kept in the destination folder
+pub const MANIFEST_FILENAME: &'static str = ".casm-manifest.toml";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// A file that has been synced
+pub struct Entry {
+    /// The source file, relative to `source_folder`
+    pub source: PathBuf,
+    /// The synced file, relative to `dest_folder`
+    pub dest: PathBuf,
+    /// The size of the source when it was synced
+    pub size: u64,
+    /// When the source was last modified when it was synced, in seconds since the epoch
+    pub modified: u64,
+    /// The `cache::content_hash` of the source when it was synced, if it could be read
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// The `ConvertProfile::fingerprint` of the settings that converted the file, if it was
+    /// converted rather than copied
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+/// The layout of the manifest file
+struct Contents {
+    #[serde(default)]
+    file: Vec<Entry>,
+}
+
+#[derive(Debug)]
+/// A record of the files that have been synced into a destination, so that an interrupted
+/// sync can tell what it finished
+pub struct Manifest {
+    path: PathBuf,
+    /// The synced files, by source path relative to `source_folder`
+    pub entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Manifest {
+    /// Reads the manifest in a destination folder, or starts an empty one if there isn't one yet
+    ///
+    /// # Arguments
+    ///
+    /// * `dest_folder` - The folder that files are synced into
+    pub fn load(dest_folder: &Path) -> Result<Manifest> {
+        let mut manifest = Manifest::new(dest_folder);
+        let path = manifest.path.clone();
+        if path.is_file() {
+            let mut contents = String::new();
+            File::open(&path)
+                .and_then(|mut f| f.read_to_string(&mut contents))
+                .chain_err(|| format!("Unable to read {}", path.display()))?;
+            let contents: Contents = toml::from_str(&contents).chain_err(|| {
+                format!("Unable to parse {}", path.display())
+            })?;
+            for entry in contents.file {
+                manifest.entries.insert(entry.source.clone(), entry);
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Starts an empty manifest for a destination folder, replacing any that is there when it is
+    /// saved
+    ///
+    /// # Arguments
+    ///
+    /// * `dest_folder` - The folder that files are synced into
+    pub fn new(dest_folder: &Path) -> Manifest {
+        Manifest {
+            path: dest_folder.join(MANIFEST_FILENAME),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Records that a file has been synced
+    ///
+    /// # Arguments
+    ///
+    /// * `source_folder` - The folder that `source` is in
+    /// * `dest_folder` - The folder that `dest` is in
+    /// * `source` - The source music file
+    /// * `dest` - The synced file
+    /// * `fingerprint` - The fingerprint of the settings that converted it, if it was converted
+    pub fn record(
+        &mut self,
+        source_folder: &Path,
+        dest_folder: &Path,
+        source: &Path,
+        dest: &Path,
+        fingerprint: Option<String>,
+    ) -> Result<()> {
+        let metadata = fs::metadata(source).chain_err(|| {
+            format!("Unable to read {}", source.display())
+        })?;
+        let modified = modified_secs(&metadata);
+        let hash = cache::content_hash(source).ok();
+        let source = source.strip_prefix(source_folder).unwrap_or(source);
+        let dest = dest.strip_prefix(dest_folder).unwrap_or(dest);
+        self.entries.insert(
+            source.to_path_buf(),
+            Entry {
+                source: source.to_path_buf(),
+                dest: dest.to_path_buf(),
+                size: metadata.len(),
+                modified: modified,
+                hash: hash,
+                fingerprint: fingerprint,
+            },
+        );
+        Ok(())
+    }
+
+    /// Finds the entry of a file that has been synced before under another name, and since
+    /// moved in the source.  The old source must be gone, its synced copy must still be there,
+    /// and the size and modification time must match, as they survive a move.  As these can
+    /// match by chance, the contents of the source must also hash the same; entries recorded
+    /// without a hash are never taken to have moved.
+    ///
+    /// # Arguments
+    ///
+    /// * `source_folder` - The folder that `source` is in
+    /// * `dest_folder` - The folder that synced files are in
+    /// * `source` - The source music file, which has no synced copy yet
+    pub fn find_moved(
+        &self,
+        source_folder: &Path,
+        dest_folder: &Path,
+        source: &Path,
+    ) -> Option<&Entry> {
+        let metadata = match fs::metadata(source) {
+            Ok(metadata) => metadata,
+            Err(_) => return None,
+        };
+        let (size, modified) = (metadata.len(), modified_secs(&metadata));
+        let mut hash = None;
+        self.entries.values().find(|entry| {
+            if entry.size != size || entry.modified != modified || entry.hash.is_none() ||
+                source_folder.join(&entry.source).exists() ||
+                !dest_folder.join(&entry.dest).is_file()
+            {
+                return false;
+            }
+            // Only hashed once a candidate turns up, as it reads the whole file
+            if hash.is_none() {
+                hash = Some(cache::content_hash(source).ok());
+            }
+            hash.as_ref() == Some(&entry.hash)
+        })
+    }
+
+    /// Whether a synced copy was converted with settings other than the given ones.  A file
+    /// recorded without a fingerprint gives `None`, as it may be a copy or a conversion from
+    /// before fingerprints were recorded, and only the synced copy itself can tell.
+    ///
+    /// # Arguments
+    ///
+    /// * `source_folder` - The folder that `source` is in
+    /// * `dest_folder` - The folder that `dest` is in
+    /// * `source` - The source music file
+    /// * `dest` - Its synced copy
+    /// * `fingerprint` - The fingerprint of the current settings
+    pub fn is_stale(
+        &self,
+        source_folder: &Path,
+        dest_folder: &Path,
+        source: &Path,
+        dest: &Path,
+        fingerprint: &str,
+    ) -> Option<bool> {
+        let source = source.strip_prefix(source_folder).unwrap_or(source);
+        let dest = dest.strip_prefix(dest_folder).unwrap_or(dest);
+        match self.entries.get(source) {
+            Some(entry) if entry.dest == dest => {
+                entry.fingerprint.as_ref().map(|f| f != fingerprint)
+            }
+            _ => Some(false),
+        }
+    }
+
+    /// Forgets the entry whose synced copy is at a path, i.e. once it has been moved, and
+    /// returns it
+    ///
+    /// # Arguments
+    ///
+    /// * `dest_folder` - The folder that `dest` is in
+    /// * `dest` - The synced file
+    pub fn forget_dest(&mut self, dest_folder: &Path, dest: &Path) -> Option<Entry> {
+        let dest = dest.strip_prefix(dest_folder).unwrap_or(dest);
+        let sources: Vec<PathBuf> = self.entries
+            .values()
+            .filter(|entry| entry.dest == dest)
+            .map(|entry| entry.source.clone())
+            .collect();
+        let mut forgotten = None;
+        for source in sources {
+            forgotten = self.entries.remove(&source);
+        }
+        forgotten
+    }
+
+    /// Writes the manifest.  It is written to a temporary file first, so an interruption can't
+    /// leave a half-written manifest behind.
+    pub fn save(&self) -> Result<()> {
+        let contents = Contents { file: self.entries.values().cloned().collect() };
+        let contents = toml::to_string(&contents).chain_err(
+            || "Unable to serialize the manifest",
+        )?;
+        let temp = self.path.with_extension("toml.tmp");
+        fs::create_dir_all(self.path.parent().unwrap_or_else(|| Path::new(".")))
+            .and_then(|_| File::create(&temp))
+            .and_then(|mut f| f.write_all(contents.as_bytes()))
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .chain_err(|| format!("Unable to write {}", self.path.display()))
+    }
+}
+
+/// When a file was last modified, in seconds since the epoch, or 0 if that isn't known
+///
+/// # Arguments
+///
+/// * `metadata` - The file's metadata
+fn modified_secs(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use super::{MANIFEST_FILENAME, Manifest};
+
+    #[test]
+    fn test_round_trip() {
+        let dest = Path::new("/tmp/casm-test-manifest");
+        fs::create_dir_all(dest).unwrap();
+        let _ = fs::remove_file(dest.join(MANIFEST_FILENAME));
+
+        let mut manifest = Manifest::load(dest).unwrap();
+        assert!(manifest.entries.is_empty());
+        manifest
+            .record(
+                Path::new("test-files"),
+                dest,
+                Path::new("test-files/folder1/How Doth The Little Crocodile.mp3"),
+                &dest.join("folder1/How Doth The Little Crocodile.mp3"),
+                Some("codec=OPUS".to_owned()),
+            )
+            .unwrap();
+        manifest.save().unwrap();
+
+        let manifest = Manifest::load(dest).unwrap();
+        let source = PathBuf::from("folder1/How Doth The Little Crocodile.mp3");
+        let entry = &manifest.entries[&source];
+        assert_eq!(entry.dest, source);
+        assert!(entry.size > 0);
+        assert_eq!(entry.fingerprint, Some("codec=OPUS".to_owned()));
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let dest = Path::new("/tmp/casm-test-stale");
+        let source = Path::new("test-files/folder1/How Doth The Little Crocodile.mp3");
+        let synced = dest.join("folder1/How Doth The Little Crocodile.opus");
+        let mut manifest = Manifest::new(dest);
+        manifest
+            .record(
+                Path::new("test-files"),
+                dest,
+                source,
+                &synced,
+                Some("bit_rate=320".to_owned()),
+            )
+            .unwrap();
+
+        let stale = |manifest: &Manifest, fingerprint| {
+            manifest.is_stale(Path::new("test-files"), dest, source, &synced, fingerprint)
+        };
+        assert_eq!(stale(&manifest, "bit_rate=320"), Some(false));
+        assert_eq!(stale(&manifest, "bit_rate=128"), Some(true));
+
+        // A conversion recorded without a fingerprint can't be told from the manifest
+        manifest
+            .record(Path::new("test-files"), dest, source, &synced, None)
+            .unwrap();
+        assert_eq!(stale(&manifest, "bit_rate=128"), None);
+
+        // Nor can a copy, which is recorded the same way
+        let copied = dest.join("folder1/How Doth The Little Crocodile.mp3");
+        manifest
+            .record(Path::new("test-files"), dest, source, &copied, None)
+            .unwrap();
+        assert_eq!(
+            manifest.is_stale(Path::new("test-files"), dest, source, &copied, "bit_rate=128"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_find_moved() {
+        let dest = Path::new("/tmp/casm-test-moved");
+        let synced = dest.join("Old Folder/Crocodile.mp3");
+        fs::create_dir_all(synced.parent().unwrap()).unwrap();
+        fs::File::create(&synced).unwrap();
+
+        let source = Path::new("test-files/folder1/How Doth The Little Crocodile.mp3");
+        let mut manifest = Manifest::new(dest);
+        manifest
+            .record(Path::new("test-files"), dest, source, &synced, None)
+            .unwrap();
+        // Pretend that it was synced from somewhere that no longer exists
+        let mut entry = manifest.entries.values().next().unwrap().clone();
+        manifest.entries.clear();
+        entry.source = PathBuf::from("Old Folder/Crocodile.mp3");
+        manifest.entries.insert(entry.source.clone(), entry.clone());
+
+        let found = manifest.find_moved(Path::new("test-files"), dest, source).unwrap();
+        assert_eq!(found.dest, PathBuf::from("Old Folder/Crocodile.mp3"));
+
+        // The same size and modification time isn't enough if the contents differ
+        entry.hash = Some("0".repeat(64));
+        manifest.entries.insert(entry.source.clone(), entry.clone());
+        assert!(manifest.find_moved(Path::new("test-files"), dest, source).is_none());
+        entry.hash = None;
+        manifest.entries.insert(entry.source.clone(), entry);
+        assert!(manifest.find_moved(Path::new("test-files"), dest, source).is_none());
+
+        assert!(manifest.forget_dest(dest, &synced).is_some());
+        assert!(manifest.entries.is_empty());
+    }
+}
@@ -1,15 +1,26 @@
 extern crate ffmpeg;
-extern crate mime_guess;
 
 pub use errors::*;
 
+use cache::{self, Cache};
+use cancel::Cancel;
 use config;
+use exclude::Exclude;
 use ffmpeg::codec;
-use regex::RegexSet;
+use libc;
+use observer::Observer;
+use sniff::{self, Detection};
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use transcoder;
-use unicase::UniCase;
+
+/// The prefix of files that are still being written
+pub const PARTIAL_PREFIX: &'static str = ".casm-partial-";
 
 #[derive(Debug, Eq, PartialEq, Hash)]
 /// A struct that holds a music file
@@ -17,20 +28,80 @@ pub struct Musicfile {
     pub filename: PathBuf,
 }
 
+#[derive(Debug)]
+/// The stream information and tags read from a music file
+pub struct Probe {
+    /// The short name of the container format, i.e. `ogg` or `flac`
+    pub container: String,
+    /// The codec of the best audio stream
+    pub codec: codec::id::Id,
+    /// The overall bit rate in bits per second, if known
+    pub bit_rate: Option<usize>,
+    /// The sample rate of the best audio stream in Hz, or 0 if the stream doesn't say
+    pub sample_rate: u32,
+    /// The number of channels in the best audio stream, or 0 if the stream doesn't say
+    pub channels: u16,
+    /// The length of the track, if the container knows it
+    pub duration: Option<Duration>,
+    /// The container and audio stream tags, with lowercased keys
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+/// What processing a music file does
+pub enum Action {
+    /// The file is already acceptable and is copied to this path
+    Copy(PathBuf),
+    /// The file is converted to the target format at this path
+    Transcode(PathBuf),
+    /// There is already a file at this path, so it is left alone
+    Keep(PathBuf),
+    /// The file was synced before under another name, so the synced copy at the first path is
+    /// moved to the second instead of being made again
+    Move(PathBuf, PathBuf),
+    /// The file matches this exclude rule, or doesn't fit in `max_size`, so it is not synced
+    Exclude(String),
+}
+
+impl Action {
+    /// The path of the file in the destination, if there is one
+    pub fn dest(&self) -> Option<&Path> {
+        match *self {
+            Action::Copy(ref dest) |
+            Action::Transcode(ref dest) |
+            Action::Keep(ref dest) |
+            Action::Move(_, ref dest) => Some(dest),
+            Action::Exclude(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Copy(ref dest) => write!(f, "copy to {}", dest.display()),
+            Action::Transcode(ref dest) => write!(f, "transcode to {}", dest.display()),
+            Action::Keep(ref dest) => write!(f, "keep existing {}", dest.display()),
+            Action::Move(ref from, ref to) => {
+                write!(f, "move {} to {}", from.display(), to.display())
+            }
+            Action::Exclude(ref rule) => write!(f, "exclude by {}", rule),
+        }
+    }
+}
+
 impl Musicfile {
-    /// Creates a new Musicfile, if it doesn't match the `exclude` regex
+    /// Creates a new Musicfile, if it is music and its path doesn't match any of the `exclude`
+    /// rules
     ///
     /// # Arguments
     ///
     /// * `filename` - The relative path of the music file
-    /// * `exclude` - A regex to exclude
-    pub fn new(filename: PathBuf, exclude: &Option<RegexSet>) -> Option<Musicfile> {
-        if mime_guess::guess_mime_type(&filename).type_() == "audio" {
-            if let Some(ref exclude) = *exclude {
-                if exclude.is_match(filename.to_str().unwrap_or("")) {
-                    return None;
-                }
-            }
+    /// * `exclude` - The rules to exclude
+    /// * `detection` - How to tell whether the file is music
+    pub fn new(filename: PathBuf, exclude: &Exclude, detection: Detection) -> Option<Musicfile> {
+        if exclude.matches_path(&filename).is_none() && sniff::is_audio(&filename, detection)
+        {
             return Some(Musicfile { filename: filename });
         }
         None
@@ -44,64 +115,311 @@ impl Musicfile {
     /// * `src` - The path to which `filename` is relative.
     /// * `dest` - The path that the relative `filename` will be copied into
     /// * `convert_profile` - Conversion settings
+    /// * `exclude` - The rules to exclude, of which only the tag rules are checked here
+    /// * `observer` - Told how the copy or conversion is progressing
     pub fn process_file(
         &self,
         src: &str,
         dest: &str,
         convert_profile: &config::ConvertProfile,
-    ) -> Result<()> {
-        let codec = self.get_codec().ok_or("Failed to get codec")?;
-        let codec_info = ::ALL_CODECS.get(&UniCase(codec.name())).ok_or(
-            "Not an acceptable music file",
-        )?;
-        // This transmute should be safe as `get` will not store the reference with
-        // the expanded lifetime. This is due to `Borrow` being overly strict and
-        // can't have an impl for `&'static str` to `Borrow<&'a str>`.
-        let key: &str = &convert_profile.target_format.to_string();
-        let key = unsafe { ::std::mem::transmute::<_, &'static str>(key) };
-        let target_codec = ::ALL_CODECS.get(&UniCase(key)).ok_or(
+        exclude: &Exclude,
+        observer: &Observer,
+    ) -> Result<Action> {
+        let action = self.plan(src, dest, convert_profile, exclude)?;
+        self.execute(&action, convert_profile, None, observer, &Cancel::new())?;
+        Ok(action)
+    }
+
+    /// Analyzes the music file to determine whether it would be copied, converted, left alone or
+    /// excluded, without touching the destination
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - The path to which `filename` is relative.
+    /// * `dest` - The path that the relative `filename` will be copied into
+    /// * `convert_profile` - Conversion settings
+    /// * `exclude` - The rules to exclude, of which only the tag rules are checked here
+    pub fn plan(
+        &self,
+        src: &str,
+        dest: &str,
+        convert_profile: &config::ConvertProfile,
+        exclude: &Exclude,
+    ) -> Result<Action> {
+        let probe = self.probe().chain_err(|| "Failed to get codec")?;
+        self.plan_probed(&probe, src, dest, convert_profile, exclude)
+    }
+
+    /// Decides what `plan` would, from what `probe` has already read from the file
+    ///
+    /// # Arguments
+    ///
+    /// * `probe` - What `probe` read from the music file
+    /// * `src` - The path to which `filename` is relative.
+    /// * `dest` - The path that the relative `filename` will be copied into
+    /// * `convert_profile` - Conversion settings
+    /// * `exclude` - The rules to exclude, of which only the tag rules are checked here
+    pub fn plan_probed(
+        &self,
+        probe: &Probe,
+        src: &str,
+        dest: &str,
+        convert_profile: &config::ConvertProfile,
+        exclude: &Exclude,
+    ) -> Result<Action> {
+        if let Some(rule) = exclude.matches_probe(probe) {
+            return Ok(Action::Exclude(rule));
+        }
+        let codec = probe.codec;
+        let codec_info = ::find_codec(codec.name()).ok_or("Not an acceptable music file")?;
+        let target_codec = ::find_codec(&convert_profile.target_format).ok_or(
             "Not an acceptable target format",
         )?;
         let dest_prefix = PathBuf::from(dest).join(self.filename.strip_prefix(src).chain_err(
             || "Could not strip prefix from filename",
         )?);
-        fs::create_dir_all(&dest_prefix.parent().ok_or(
-            "Cannot get parent of root or prefix",
-        )?).chain_err(|| "Could not create destination")?;
         if codec_info.is_acceptable(&convert_profile.acceptable_formats) {
             let dest = dest_prefix.with_extension(codec_info.extension);
-            if self.should_write(&dest) {
-                fs::copy(&self.filename, dest).chain_err(
-                    || "Could not copy file",
-                )?;
+            if !self.should_write(&dest) {
+                return Ok(Action::Keep(dest));
             }
+            Ok(Action::Copy(dest))
         } else {
             let dest = dest_prefix.with_extension(target_codec.extension);
-            if self.should_write(&dest) {
-                ffmpeg::init().unwrap();
-                transcoder::convert(
-                    self.filename.to_str().ok_or("Invalid filename")?,
-                    dest.to_str().ok_or("Invalid destination")?,
-                    "anull",
-                    convert_profile.bit_rate * 1024,
-                );
+            if !self.should_write(&dest) {
+                return Ok(Action::Keep(dest));
+            }
+            Ok(Action::Transcode(dest))
+        }
+    }
+
+    /// Carries out a planned action, creating the destination folder as needed.  The file is
+    /// written under a temporary name and renamed into place once it is complete, so an
+    /// interrupted sync never leaves a partial file where a finished one is expected.
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - What `plan` decided to do with the file
+    /// * `convert_profile` - Conversion settings
+    /// * `cache` - Where conversions are looked up before converting, and stored after
+    /// * `observer` - Told how the copy or conversion is progressing
+    /// * `cancel` - Abandons a conversion in progress when raised
+    pub fn execute(
+        &self,
+        action: &Action,
+        convert_profile: &config::ConvertProfile,
+        cache: Option<&Cache>,
+        observer: &Observer,
+        cancel: &Cancel,
+    ) -> Result<()> {
+        let dest = match *action {
+            Action::Copy(ref dest) |
+            Action::Transcode(ref dest) => dest,
+            Action::Move(ref from, ref to) => {
+                create_parent(to)?;
+                fs::rename(from, to).chain_err(|| {
+                    format!("Could not move {}", from.display())
+                })?;
+                return Ok(());
             }
+            Action::Keep(_) | Action::Exclude(_) => return Ok(()),
+        };
+        create_parent(dest)?;
+        let partial = partial_path(dest)?;
+        let total = fs::metadata(&self.filename).map(|m| m.len()).unwrap_or(0);
+
+        let result = match *action {
+            Action::Transcode(_) => {
+                self.transcode(&partial, convert_profile, cache, observer, cancel, total)
+            }
+            _ => {
+                fs::copy(&self.filename, &partial).map(|_| ()).or_else(
+                    |e| if is_full(&e) {
+                        bail!(ErrorKind::NoSpace)
+                    } else {
+                        Err(e).chain_err(|| "Could not copy file")
+                    },
+                )
+            }
+        };
+
+        let result = result.and_then(|_| {
+            fs::rename(&partial, dest).or_else(|e| if is_full(&e) {
+                bail!(ErrorKind::NoSpace)
+            } else {
+                Err(e).chain_err(|| "Could not move the finished file into place")
+            })
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&partial);
+            if cancel.is_cancelled() {
+                bail!(ErrorKind::Cancelled);
+            }
+            return Err(e);
         }
+        observer.progressed(self, total, total);
         Ok(())
     }
 
-    /// Gets the codec from the music file via ffmpeg
-    fn get_codec(&self) -> Option<codec::id::Id> {
-        ffmpeg::init().unwrap();
-        match ffmpeg::format::input(&self.filename) {
-            Ok(context) => {
-                if let Some(stream) = context.streams().best(ffmpeg::media::Type::Audio) {
-                    Some(stream.codec().id())
-                } else {
-                    None
+    /// Converts the music file to a path, copying the conversion from the cache instead if it is
+    /// there, and storing it in the cache if not
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Where to write the converted file
+    /// * `convert_profile` - Conversion settings
+    /// * `cache` - Where conversions are looked up and stored
+    /// * `observer` - Told how the conversion is progressing
+    /// * `cancel` - Abandons the conversion when raised
+    /// * `total` - The size of the music file, for reporting progress
+    fn transcode(
+        &self,
+        output: &Path,
+        convert_profile: &config::ConvertProfile,
+        cache: Option<&Cache>,
+        observer: &Observer,
+        cancel: &Cancel,
+        total: u64,
+    ) -> Result<()> {
+        let extension = output.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let cached = cache.and_then(|cache| match Cache::key(&self.filename, convert_profile) {
+            Ok(key) => Some((cache, key)),
+            Err(e) => {
+                warn!("{}; converting without the cache", e);
+                None
+            }
+        });
+        if let Some((cache, ref key)) = cached {
+            match cache.fetch(key, extension, output) {
+                Ok(true) => {
+                    info!("Copied {} from the cache", self.filename.display());
+                    return Ok(());
                 }
+                Ok(false) => {}
+                Err(e) => warn!("{}; converting instead", e),
             }
-            Err(_) => None,
+        }
+
+        ffmpeg::init().unwrap();
+        transcoder::convert(
+            self.filename.to_str().ok_or("Invalid filename")?,
+            output.to_str().ok_or("Invalid destination")?,
+            convert_profile.filter(),
+            convert_profile.bit_rate * 1024,
+            &|done| {
+                observer.progressed(self, done, total);
+                !cancel.is_cancelled()
+            },
+        )?;
+
+        if let Some((cache, ref key)) = cached {
+            if let Err(e) = cache.store(key, extension, output) {
+                warn!("{}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a synced copy of the music file is whole.  A copy must have the same contents
+    /// as the music file, and a conversion must decode all the way through, to within
+    /// `tolerance` seconds of the music file's length.
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - The synced copy
+    /// * `converted` - Whether it was converted, rather than copied
+    /// * `duration` - The length of the music file, if it is known
+    /// * `tolerance` - How many seconds the lengths may differ by
+    pub fn verify(
+        &self,
+        dest: &Path,
+        converted: bool,
+        duration: Option<Duration>,
+        tolerance: f64,
+    ) -> Result<()> {
+        if !converted {
+            if cache::content_hash(&self.filename)? != cache::content_hash(dest)? {
+                bail!("{} does not match its source", dest.display());
+            }
+            return Ok(());
+        }
+
+        let decoded = transcoder::decoded_duration(dest.to_str().ok_or("Invalid destination")?)?;
+        if let Some(duration) = duration {
+            let seconds = |d: Duration| d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9;
+            if (seconds(decoded) - seconds(duration)).abs() > tolerance {
+                bail!(
+                    "{} is {:.1}s long, but its source is {:.1}s long",
+                    dest.display(),
+                    seconds(decoded),
+                    seconds(duration)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the format, codec, stream details and tags of the music file via ffmpeg
+    pub fn probe(&self) -> Result<Probe> {
+        ffmpeg::init().unwrap();
+        let context = ffmpeg::format::input(&self.filename).chain_err(
+            || "Could not open file",
+        )?;
+        let stream = context.streams().best(ffmpeg::media::Type::Audio).ok_or(
+            "No audio stream",
+        )?;
+
+        let mut tags = HashMap::new();
+        for (key, value) in context.metadata().iter().chain(stream.metadata().iter()) {
+            tags.insert(key.to_lowercase(), value.to_owned());
+        }
+
+        // The container duration is in AV_TIME_BASE (microsecond) units, and negative if unknown
+        let duration = if context.duration() > 0 {
+            Some(Duration::from_millis(context.duration() as u64 / 1000))
+        } else {
+            None
+        };
+
+        // Read from the stream's codec parameters, as setting up a decoder is slower and fails
+        // for codecs that this ffmpeg can't decode, which may still be copied
+        let parameters = stream.parameters();
+        let (sample_rate, channels) = unsafe {
+            let parameters = parameters.as_ptr();
+            ((*parameters).sample_rate, (*parameters).channels)
+        };
+
+        Ok(Probe {
+            container: context.format().name().to_owned(),
+            codec: parameters.id(),
+            bit_rate: if context.bit_rate() > 0 {
+                Some(context.bit_rate() as usize)
+            } else {
+                None
+            },
+            sample_rate: cmp::max(sample_rate, 0) as u32,
+            channels: cmp::max(channels, 0) as u16,
+            duration: duration,
+            tags: tags,
+        })
+    }
+
+    /// Gets the codec from the music file via ffmpeg
+    pub fn get_codec(&self) -> Option<codec::id::Id> {
+        self.probe().ok().map(|probe| probe.codec)
+    }
+
+    /// Whether the music file has been modified since a synced copy of it was written, i.e.
+    /// because it was retagged
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - The synced copy
+    pub fn is_outdated(&self, dest: &Path) -> bool {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        match (modified(&self.filename), modified(dest)) {
+            (Some(source), Some(dest)) => source > dest,
+            _ => false,
         }
     }
 
@@ -117,34 +435,114 @@ impl Musicfile {
     }
 }
 
+/// Whether an error writing to the destination was because it is full
+///
+/// # Arguments
+///
+/// * `error` - The error
+fn is_full(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::ENOSPC)
+}
+
+/// Creates the folder that a destination file goes in
+///
+/// # Arguments
+///
+/// * `dest` - The destination file
+fn create_parent(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest.parent().ok_or("Cannot get parent of root or prefix")?)
+        .chain_err(|| "Could not create destination")
+}
+
+/// Removes the folders above a file that are now empty, up to the destination folder.
+/// `remove_dir` refuses non-empty folders.
+///
+/// # Arguments
+///
+/// * `file` - The file that has been removed
+/// * `dest_folder` - The folder to stop at
+pub fn remove_empty_parents(file: &Path, dest_folder: &Path) {
+    let mut folder = file.parent();
+    while let Some(dir) = folder {
+        if dir == dest_folder || !dir.starts_with(dest_folder) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+        folder = dir.parent();
+    }
+}
+
+/// The temporary name that a file is written under until it is complete.  It keeps the
+/// extension, as ffmpeg picks the output format from it.
+///
+/// # Arguments
+///
+/// * `dest` - The path of the finished file
+fn partial_path(dest: &Path) -> Result<PathBuf> {
+    let name = dest.file_name().ok_or("Invalid destination")?;
+    Ok(dest.with_file_name(
+        format!("{}{}", PARTIAL_PREFIX, name.to_string_lossy()),
+    ))
+}
+
 #[cfg(test)]
 mod tests {
+    use exclude::Exclude;
     use ffmpeg;
-    use regex::RegexSet;
-    use super::Musicfile;
-    use std::path::PathBuf;
+    use libc;
+    use sniff::Detection;
+    use super::{Musicfile, is_full, partial_path};
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
 
     #[test]
     fn test_matches_exclude() {
         let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
-        let exclude = Some(RegexSet::new(&[r"^.*Crocodile\.mp3$"]).unwrap());
-        assert_eq!(Musicfile::new(filename, &exclude), None);
+        let exclude = Exclude::new(
+            "test-files",
+            &Some(vec![r"^.*Crocodile\.mp3$".to_owned()]),
+            &None,
+            &None,
+        ).unwrap();
+        assert_eq!(Musicfile::new(filename, &exclude, Detection::Fast), None);
     }
 
     #[test]
     fn test_not_matches_exclude() {
         let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
-        let exclude = Some(RegexSet::new(&[r"^.*Alligator\.mp3$"]).unwrap());
+        let exclude = Exclude::new(
+            "test-files",
+            &Some(vec![r"^.*Alligator\.mp3$".to_owned()]),
+            &None,
+            &None,
+        ).unwrap();
         let expected_musicfile = Musicfile { filename: filename.clone() };
-        assert_eq!(Musicfile::new(filename, &exclude), Some(expected_musicfile));
+        assert_eq!(Musicfile::new(filename, &exclude, Detection::Fast), Some(expected_musicfile));
     }
 
     #[test]
     fn test_no_exclude() {
         let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
-        let exclude = None;
+        let exclude = Exclude::default();
         let expected_musicfile = Musicfile { filename: filename.clone() };
-        assert_eq!(Musicfile::new(filename, &exclude), Some(expected_musicfile));
+        assert_eq!(Musicfile::new(filename, &exclude, Detection::Fast), Some(expected_musicfile));
+    }
+
+    #[test]
+    fn test_matches_exclude_glob() {
+        let filename = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
+        let exclude = Exclude::new("test-files", &None, &Some(vec!["folder1/*".to_owned()]), &None)
+            .unwrap();
+        assert_eq!(Musicfile::new(filename, &exclude, Detection::Fast), None);
+    }
+
+    #[test]
+    fn test_thorough_not_music() {
+        let filename = PathBuf::from("test-files/notmusic.txt");
+        let exclude = Exclude::default();
+        assert_eq!(Musicfile::new(filename, &exclude, Detection::Thorough), None);
     }
 
     #[test]
@@ -156,4 +554,43 @@ mod tests {
         let expected_codec = ffmpeg::codec::Id::MP3;
         assert_eq!(musicfile.get_codec(), Some(expected_codec));
     }
+
+    #[test]
+    fn test_probe() {
+        let musicfile = Musicfile { filename: PathBuf::from("test-files/Synth Synth Short.mp3") };
+        let probe = musicfile.probe().unwrap();
+        assert_eq!(probe.codec, ffmpeg::codec::Id::MP3);
+        assert!(probe.sample_rate > 0);
+        assert!(probe.channels > 0);
+    }
+
+    #[test]
+    fn test_is_outdated() {
+        let source = Path::new("test-files/folder1/How Doth The Little Crocodile.mp3");
+        let dest = Path::new("/tmp/casm-test-outdated-dest.mp3");
+        let retagged = Path::new("/tmp/casm-test-outdated-source.mp3");
+        fs::copy(source, dest).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        fs::copy(source, retagged).unwrap();
+
+        let musicfile = Musicfile { filename: retagged.to_path_buf() };
+        assert!(musicfile.is_outdated(dest));
+        let musicfile = Musicfile { filename: dest.to_path_buf() };
+        assert!(!musicfile.is_outdated(retagged));
+        assert!(!musicfile.is_outdated(Path::new("/tmp/casm-test-outdated-missing.mp3")));
+    }
+
+    #[test]
+    fn test_partial_path() {
+        assert_eq!(
+            partial_path(Path::new("dest/Artist/Song.opus")).unwrap(),
+            PathBuf::from("dest/Artist/.casm-partial-Song.opus")
+        );
+    }
+
+    #[test]
+    fn test_is_full() {
+        assert!(is_full(&io::Error::from_raw_os_error(libc::ENOSPC)));
+        assert!(!is_full(&io::Error::new(io::ErrorKind::NotFound, "gone")));
+    }
 }
@@ -0,0 +1,216 @@
+pub use errors::*;
+
+use musicfile::{Action, Musicfile, Probe};
+use pbr::ProgressBar;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/// Receives events as files are planned and synced.  Every method does nothing by default, so
+/// an observer only implements the events it cares about.  Events for different files arrive
+/// from several threads at once.
+pub trait Observer: Sync {
+    /// Called before any file is processed
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The number of files that will be processed
+    fn begin(&self, count: usize) {
+        let _ = count;
+    }
+
+    /// Called when planning has decided what to do with a file
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The source music file
+    /// * `action` - What syncing will do with it
+    /// * `probe` - What was read from the file, if it could be read
+    fn planned(&self, file: &Musicfile, action: &Action, probe: Option<&Probe>) {
+        let _ = (file, action, probe);
+    }
+
+    /// Called when a file starts being copied or converted
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The source music file
+    /// * `action` - What is being done with it
+    fn started(&self, file: &Musicfile, action: &Action) {
+        let _ = (file, action);
+    }
+
+    /// Called as a file is copied or converted
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The source music file
+    /// * `done` - How many bytes of the source have been handled
+    /// * `total` - The size of the source in bytes
+    fn progressed(&self, file: &Musicfile, done: u64, total: u64) {
+        let _ = (file, done, total);
+    }
+
+    /// Called when a file has been copied or converted
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The source music file
+    /// * `action` - What was done with it
+    fn finished(&self, file: &Musicfile, action: &Action) {
+        let _ = (file, action);
+    }
+
+    /// Called when a file is left alone, because it is already in the destination or is excluded
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The source music file
+    /// * `action` - Why it was left alone
+    fn skipped(&self, file: &Musicfile, action: &Action) {
+        let _ = (file, action);
+    }
+
+    /// Called when a file could not be planned, copied or converted
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The source music file
+    /// * `error` - What went wrong
+    fn failed(&self, file: &Musicfile, error: &Error) {
+        let _ = (file, error);
+    }
+
+    /// Called after every file has been processed
+    fn end(&self) {}
+}
+
+/// An observer that ignores every event
+pub struct Quiet;
+
+impl Observer for Quiet {}
+
+lazy_static! {
+    /// The progress bar on the terminal, if one is shown.  There is only one terminal, so every
+    /// `Progress` shares it, and log messages are written above it.
+    static ref BAR: Mutex<Option<ProgressBar<io::Stdout>>> = Mutex::new(None);
+}
+
+/// Writes a line to stderr.  If a progress bar is shown, it is cleared first and drawn again
+/// below the line, so that the two don't run into each other.
+///
+/// # Arguments
+///
+/// * `line` - The line to write, without a newline
+pub fn write_above_bar(line: &str) {
+    // There is nowhere to report a line that can't be written
+    match *BAR.lock().unwrap() {
+        Some(ref mut pb) => {
+            let stdout = io::stdout();
+            let _ = write!(stdout.lock(), "\r\x1b[2K").and_then(|_| stdout.lock().flush());
+            let _ = writeln!(io::stderr(), "{}", line);
+            pb.tick();
+        }
+        None => {
+            let _ = writeln!(io::stderr(), "{}", line);
+        }
+    }
+}
+
+/// Shows a progress bar on the terminal.  Log messages are written above the bar while it is
+/// shown, and failures are listed again in the summary at the end.
+pub struct Progress;
+
+impl Progress {
+    /// Creates the observer.  The bar appears when processing begins.
+    pub fn new() -> Progress {
+        Progress
+    }
+
+    /// Moves the bar on by one file
+    fn inc(&self) {
+        if let Some(ref mut pb) = *BAR.lock().unwrap() {
+            pb.inc();
+        }
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new()
+    }
+}
+
+impl Observer for Progress {
+    fn begin(&self, count: usize) {
+        let mut pb = ProgressBar::new(count as u64);
+        pb.tick_format("▏▎▍▌▋▊▉██▉▊▋▌▍▎▏");
+        pb.show_message = true;
+        *BAR.lock().unwrap() = Some(pb);
+    }
+
+    fn started(&self, file: &Musicfile, _: &Action) {
+        if let Some(ref mut pb) = *BAR.lock().unwrap() {
+            pb.message(&format!(
+                "Processing {}: ",
+                file.filename
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("invalid filename")
+            ));
+        }
+    }
+
+    fn finished(&self, _: &Musicfile, _: &Action) {
+        self.inc();
+    }
+
+    fn skipped(&self, _: &Musicfile, _: &Action) {
+        self.inc();
+    }
+
+    fn failed(&self, _: &Musicfile, _: &Error) {
+        self.inc();
+    }
+
+    fn end(&self) {
+        let mut bar = BAR.lock().unwrap();
+        if let Some(ref mut pb) = *bar {
+            pb.finish();
+        }
+        *bar = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use musicfile::{Action, Musicfile};
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use super::Observer;
+
+    /// Records the names of the events it receives
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Observer for Recorder {
+        fn skipped(&self, _: &Musicfile, action: &Action) {
+            self.events.lock().unwrap().push(format!("skipped: {}", action));
+        }
+    }
+
+    #[test]
+    fn test_default_methods() {
+        let recorder = Recorder { events: Mutex::new(Vec::new()) };
+        let file = Musicfile { filename: PathBuf::from("test-files/a.mp3") };
+        let action = Action::Keep(PathBuf::from("dest/a.mp3"));
+        recorder.begin(1);
+        recorder.started(&file, &action);
+        recorder.skipped(&file, &action);
+        recorder.end();
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec!["skipped: keep existing dest/a.mp3".to_owned()]
+        );
+    }
+}
@@ -0,0 +1,544 @@
+pub use errors::*;
+
+use config::PlaylistExport;
+use musicfile::Action;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+/// The playlist formats that can be read
+pub enum Format {
+    /// `.m3u` and `.m3u8`, one path per line
+    M3u,
+    /// `.pls`, an INI file with `FileN=` entries
+    Pls,
+    /// `.xspf`, XML with `<location>` elements
+    Xspf,
+}
+
+impl Format {
+    /// Guesses the playlist format from a file extension
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The playlist file
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        match extension.as_ref().map(|extension| extension.as_str()) {
+            Some("m3u") | Some("m3u8") => Some(Format::M3u),
+            Some("pls") => Some(Format::Pls),
+            Some("xspf") => Some(Format::Xspf),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+/// The text encodings that playlists can be written in
+pub enum Encoding {
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// UTF-8 with a byte order mark, which some players need to notice it isn't Latin-1
+    #[serde(rename = "utf-8-bom")]
+    Utf8Bom,
+    /// ISO-8859-1, for old players.  Characters outside of it are written as `?`.
+    #[serde(rename = "latin1")]
+    Latin1,
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::Utf8
+    }
+}
+
+impl Encoding {
+    /// Encodes playlist text
+    ///
+    /// # Arguments
+    ///
+    /// * `contents` - The text of the playlist
+    fn encode(&self, contents: &str) -> Vec<u8> {
+        match *self {
+            Encoding::Utf8 => contents.as_bytes().to_vec(),
+            Encoding::Utf8Bom => {
+                let mut bytes = b"\xEF\xBB\xBF".to_vec();
+                bytes.extend(contents.as_bytes());
+                bytes
+            }
+            Encoding::Latin1 => {
+                contents
+                    .chars()
+                    .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+/// A playlist read from the source folder
+pub struct Playlist {
+    /// Where the playlist was read from
+    pub path: PathBuf,
+    /// The entries, in order, as written in the playlist
+    pub entries: Vec<String>,
+}
+
+impl Playlist {
+    /// Reads a playlist file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The playlist file, which must have one of the extensions in `Format`
+    pub fn read(path: &Path) -> Result<Playlist> {
+        let format = Format::from_path(path).ok_or("Not a playlist")?;
+        let mut bytes = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .chain_err(|| "Unable to read playlist")?;
+
+        let contents = decode(&bytes);
+        let entries = match format {
+            Format::M3u => parse_m3u(&contents),
+            Format::Pls => parse_pls(&contents),
+            Format::Xspf => parse_xspf(&contents),
+        };
+
+        Ok(Playlist {
+            path: path.to_path_buf(),
+            entries: entries,
+        })
+    }
+
+    /// Finds the file that a playlist entry refers to.  Relative entries are tried against the
+    /// playlist's folder and then `source_folder`, and absolute entries as they are and then
+    /// as relative to `source_folder`.  Only existing files inside `source_folder` resolve.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - One of `entries`
+    /// * `source_folder` - The folder that all music files are relative to
+    pub fn resolve(&self, entry: &str, source_folder: &Path) -> Option<PathBuf> {
+        let entry = if entry.starts_with("file://") {
+            file_uri_to_path(entry)
+        } else if entry.contains("://") {
+            return None;
+        } else {
+            entry.replace('\\', "/")
+        };
+
+        let entry = Path::new(&entry);
+        let candidates = if entry.is_absolute() {
+            let relative: PathBuf = entry
+                .components()
+                .filter(|c| match *c {
+                    Component::RootDir | Component::Prefix(_) => false,
+                    _ => true,
+                })
+                .collect();
+            vec![entry.to_path_buf(), source_folder.join(relative)]
+        } else {
+            let folder = self.path.parent().unwrap_or_else(|| Path::new(""));
+            vec![folder.join(entry), source_folder.join(entry)]
+        };
+
+        let source_folder = normalize(source_folder);
+        candidates.into_iter().map(|c| normalize(&c)).find(|candidate| {
+            candidate.starts_with(&source_folder) && candidate.is_file()
+        })
+    }
+
+    /// Writes the playlist into the destination as M3U8, with each entry pointing at the file
+    /// that processing put there, relative to the new playlist.  Entries that weren't synced are
+    /// left out.
+    ///
+    /// # Arguments
+    ///
+    /// * `results` - What happened to each music file, by source path
+    /// * `source_folder` - The folder that all music files are relative to
+    /// * `dest_folder` - The folder that processed music files were put in
+    /// * `export` - The playlist export settings
+    pub fn export(
+        &self,
+        results: &HashMap<PathBuf, Result<Action>>,
+        source_folder: &Path,
+        dest_folder: &Path,
+        export: &PlaylistExport,
+    ) -> Result<PathBuf> {
+        let relative = match export.folder {
+            Some(ref folder) => {
+                Path::new(folder).join(self.path.file_name().ok_or("Playlist has no name")?)
+            }
+            None => {
+                normalize(&self.path)
+                    .strip_prefix(&normalize(source_folder))
+                    .chain_err(|| "Playlist is not inside the source folder")?
+                    .to_path_buf()
+            }
+        };
+        let dest = dest_folder.join(relative).with_extension("m3u8");
+
+        let synced: Vec<PathBuf> = self.entries
+            .iter()
+            .filter_map(|entry| self.resolve(entry, source_folder))
+            .filter_map(|source| results.get(&source))
+            .filter_map(|result| result.as_ref().ok())
+            .filter_map(|action| action.dest())
+            .map(|synced| synced.to_path_buf())
+            .collect();
+
+        write_m3u8(&dest, &synced, &export.path_separator, export.encoding)?;
+        Ok(dest)
+    }
+}
+
+/// Writes an M3U8 playlist, with each file relative to the playlist's folder
+///
+/// # Arguments
+///
+/// * `dest` - Where to write the playlist
+/// * `files` - The files in the playlist, in order
+/// * `path_separator` - The separator between folders in each entry
+/// * `encoding` - The text encoding of the playlist
+pub fn write_m3u8(
+    dest: &Path,
+    files: &[PathBuf],
+    path_separator: &str,
+    encoding: Encoding,
+) -> Result<()> {
+    let dest_dir = dest.parent().ok_or("Cannot get parent of playlist")?;
+
+    let mut contents = String::from("#EXTM3U\n");
+    for file in files {
+        let components: Vec<String> = relative_path(dest_dir, file)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        contents.push_str(&components.join(path_separator));
+        contents.push('\n');
+    }
+
+    fs::create_dir_all(dest_dir).chain_err(
+        || "Could not create playlist destination",
+    )?;
+    File::create(dest)
+        .and_then(|mut file| file.write_all(&encoding.encode(&contents)))
+        .chain_err(|| "Could not write playlist")
+}
+
+/// Finds the path to a file relative to a folder, using `..` where needed
+///
+/// # Arguments
+///
+/// * `from` - The folder the path should be relative to
+/// * `to` - The file the path should lead to
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(to.iter()).take_while(|&(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to[common..] {
+        relative.push(component.as_os_str());
+    }
+    relative
+}
+
+/// Removes `.` and resolves `..` components without touching the filesystem, so that the same
+/// file reached through different playlists is only synced once
+///
+/// # Arguments
+///
+/// * `path` - The path to clean up
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            _ => normalized.push(component.as_os_str()),
+        }
+    }
+    normalized
+}
+
+/// Decodes playlist text, which is UTF-8 for `.m3u8` but often Latin-1 for older `.m3u` files
+///
+/// # Arguments
+///
+/// * `bytes` - The contents of the playlist file
+fn decode(bytes: &[u8]) -> String {
+    let bytes = if bytes.starts_with(b"\xEF\xBB\xBF") {
+        &bytes[3..]
+    } else {
+        bytes
+    };
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(contents) => contents,
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// Gets the entries from an M3U playlist, skipping comments and `#EXT` directives
+///
+/// # Arguments
+///
+/// * `contents` - The text of the playlist
+fn parse_m3u(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_owned())
+        .collect()
+}
+
+/// Gets the entries from a PLS playlist, in the order of their `FileN` numbers
+///
+/// # Arguments
+///
+/// * `contents` - The text of the playlist
+fn parse_pls(contents: &str) -> Vec<String> {
+    let file = Regex::new(r"(?i)^\s*file(\d+)\s*=\s*(.*?)\s*$").unwrap();
+    let mut entries: Vec<(usize, String)> = contents
+        .lines()
+        .filter_map(|line| file.captures(line))
+        .filter_map(|captures| {
+            captures[1].parse().ok().map(
+                |n| (n, captures[2].to_owned()),
+            )
+        })
+        .filter(|&(_, ref entry)| !entry.is_empty())
+        .collect();
+    entries.sort_by_key(|&(n, _)| n);
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+/// Gets the `<location>` of each track from an XSPF playlist
+///
+/// # Arguments
+///
+/// * `contents` - The XML of the playlist
+fn parse_xspf(contents: &str) -> Vec<String> {
+    let location = Regex::new(r"(?s)<location>\s*(.*?)\s*</location>").unwrap();
+    location
+        .captures_iter(contents)
+        .map(|captures| {
+            captures[1]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+/// Turns a `file://` URI into a path, decoding `%XX` escapes
+///
+/// # Arguments
+///
+/// * `uri` - A URI starting with `file://`
+fn file_uri_to_path(uri: &str) -> String {
+    let path = &uri["file://".len()..];
+    let path = if path.starts_with("localhost/") {
+        &path["localhost".len()..]
+    } else {
+        path
+    };
+
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = iter.by_ref().take(2).collect();
+            let decoded = ::std::str::from_utf8(&hex).ok().and_then(|hex| {
+                u8::from_str_radix(hex, 16).ok()
+            });
+            match decoded {
+                Some(decoded) => bytes.push(decoded),
+                None => {
+                    bytes.push(b);
+                    bytes.extend(hex);
+                }
+            }
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use config::PlaylistExport;
+    use musicfile::Action;
+    use super::{Encoding, Format, Playlist, decode, file_uri_to_path, normalize, parse_m3u,
+                parse_pls, parse_xspf, relative_path};
+    use std::collections::HashMap;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Format::from_path(Path::new("a.M3U8")), Some(Format::M3u));
+        assert_eq!(Format::from_path(Path::new("a.pls")), Some(Format::Pls));
+        assert_eq!(Format::from_path(Path::new("a.xspf")), Some(Format::Xspf));
+        assert_eq!(Format::from_path(Path::new("a.mp3")), None);
+    }
+
+    #[test]
+    fn test_m3u() {
+        let contents = "#EXTM3U\n#EXTINF:123,Artist - Title\nArtist/Title.flac\n\n  Other.mp3  \n";
+        assert_eq!(parse_m3u(contents), vec!["Artist/Title.flac", "Other.mp3"]);
+    }
+
+    #[test]
+    fn test_pls() {
+        let contents = "[playlist]\nFile2=Second.mp3\nTitle2=Second\nfile1=First.flac\n\
+                        NumberOfEntries=2\n";
+        assert_eq!(parse_pls(contents), vec!["First.flac", "Second.mp3"]);
+    }
+
+    #[test]
+    fn test_xspf() {
+        let contents = r#"<playlist><trackList>
+            <track><location>file:///music/A%20%26%20B.flac</location></track>
+            <track><location>Rock &amp; Roll.mp3</location></track>
+        </trackList></playlist>"#;
+        assert_eq!(
+            parse_xspf(contents),
+            vec!["file:///music/A%20%26%20B.flac", "Rock & Roll.mp3"]
+        );
+    }
+
+    #[test]
+    fn test_file_uri() {
+        assert_eq!(file_uri_to_path("file:///music/A%20%26%20B.flac"), "/music/A & B.flac");
+        assert_eq!(file_uri_to_path("file://localhost/a%zz"), "/a%zz");
+    }
+
+    #[test]
+    fn test_decode_latin1() {
+        assert_eq!(decode(b"Bj\xF6rk.mp3"), "Björk.mp3");
+        assert_eq!(decode(b"\xEF\xBB\xBFBj\xC3\xB6rk.mp3"), "Björk.mp3");
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(Encoding::Utf8.encode("Björk"), b"Bj\xC3\xB6rk".to_vec());
+        assert_eq!(Encoding::Utf8Bom.encode("a"), b"\xEF\xBB\xBFa".to_vec());
+        assert_eq!(Encoding::Latin1.encode("Björk 방탄"), b"Bj\xF6rk ??".to_vec());
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path(Path::new("/dest/Playlists"), Path::new("/dest/Artist/Song.opus")),
+            PathBuf::from("../Artist/Song.opus")
+        );
+        assert_eq!(
+            relative_path(Path::new("/dest"), Path::new("/dest/Artist/Song.opus")),
+            PathBuf::from("Artist/Song.opus")
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("a/./b/../c")), PathBuf::from("a/c"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let playlist = Playlist::read(Path::new("test-files/crocodiles.m3u8")).unwrap();
+        let source_folder = Path::new("test-files");
+        let resolved: Vec<Option<PathBuf>> = playlist
+            .entries
+            .iter()
+            .map(|entry| playlist.resolve(entry, source_folder))
+            .collect();
+        assert_eq!(
+            resolved,
+            vec![
+                Some(PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3")),
+                Some(PathBuf::from("test-files/Synth Synth Short.mp3")),
+                Some(PathBuf::from("test-files/folder2/Synth Synth Short.mp3")),
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let playlist = Playlist::read(Path::new("test-files/crocodiles.m3u8")).unwrap();
+        let source_folder = Path::new("test-files");
+        let dest_folder = Path::new("/tmp/casm-test-export");
+        let crocodile = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
+        let synth = PathBuf::from("test-files/Synth Synth Short.mp3");
+        let other_synth = PathBuf::from("test-files/folder2/Synth Synth Short.mp3");
+        let read = |path: &Path| {
+            let mut contents = String::new();
+            File::open(path)
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+
+        // Conversions point at the converted file, and entries that weren't synced are left out
+        let mut results = HashMap::new();
+        results.insert(
+            crocodile.clone(),
+            Ok(Action::Transcode(
+                dest_folder.join("folder1/How Doth The Little Crocodile.opus"),
+            )),
+        );
+        results.insert(synth.clone(), Ok(Action::Exclude("exclude_globs".to_owned())));
+        results.insert(other_synth, Err("Failed to get codec".into()));
+        let export = PlaylistExport {
+            folder: None,
+            path_separator: "/".to_owned(),
+            encoding: Encoding::Utf8,
+        };
+        let _ = fs::remove_dir_all(dest_folder);
+        let written = playlist
+            .export(&results, source_folder, dest_folder, &export)
+            .unwrap();
+        assert_eq!(written, dest_folder.join("crocodiles.m3u8"));
+        assert_eq!(read(&written), "#EXTM3U\nfolder1/How Doth The Little Crocodile.opus\n");
+
+        // Kept files are still in the destination, so they stay in the playlist
+        results.insert(
+            synth.clone(),
+            Ok(Action::Keep(dest_folder.join("Synth Synth Short.mp3"))),
+        );
+        let export = PlaylistExport {
+            folder: Some("Playlists".to_owned()),
+            path_separator: "\\".to_owned(),
+            encoding: Encoding::Utf8,
+        };
+        let written = playlist
+            .export(&results, source_folder, dest_folder, &export)
+            .unwrap();
+        assert_eq!(written, dest_folder.join("Playlists/crocodiles.m3u8"));
+        assert_eq!(
+            read(&written),
+            "#EXTM3U\n..\\folder1\\How Doth The Little Crocodile.opus\n..\\Synth Synth Short.mp3\n"
+        );
+    }
+}
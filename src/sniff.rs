@@ -0,0 +1,139 @@
+extern crate ffmpeg;
+extern crate mime_guess;
+
+use ffmpeg::codec;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use unicase::UniCase;
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+/// How music files are told apart from everything else while scanning
+pub enum Detection {
+    /// Trust the file extension
+    Fast,
+    /// Read the start of the file, and ask ffmpeg if that doesn't settle it
+    Thorough,
+}
+
+impl Default for Detection {
+    fn default() -> Detection {
+        Detection::Fast
+    }
+}
+
+/// The number of bytes read from the start of a file to look for a signature
+const HEADER_LEN: usize = 12;
+
+/// Checks whether a file looks like music
+///
+/// # Arguments
+///
+/// * `path` - The file to check
+/// * `detection` - Whether to go by the extension, or by the contents
+pub fn is_audio(path: &Path, detection: Detection) -> bool {
+    match detection {
+        Detection::Fast => mime_guess::guess_mime_type(path).type_() == "audio",
+        Detection::Thorough => {
+            if !path.is_file() {
+                return false;
+            }
+            let mut header = [0; HEADER_LEN];
+            let read = match File::open(path).and_then(|mut file| file.read(&mut header)) {
+                Ok(read) => read,
+                Err(_) => return false,
+            };
+            has_audio_signature(&header[..read]) || has_audio_stream(path)
+        }
+    }
+}
+
+/// Looks for the magic bytes of a common audio container at the start of a file
+///
+/// # Arguments
+///
+/// * `header` - The first few bytes of the file
+fn has_audio_signature(header: &[u8]) -> bool {
+    let starts = |magic: &[u8]| header.starts_with(magic);
+    let at = |offset: usize, magic: &[u8]| {
+        header.len() >= offset + magic.len() && &header[offset..offset + magic.len()] == magic
+    };
+
+    starts(b"fLaC") || starts(b"OggS") || starts(b"ID3") || starts(b"wvpk") ||
+        starts(b"MAC ") || starts(b"DSD ") || starts(b"FRM8") ||
+        (starts(b"RIFF") && at(8, b"WAVE")) ||
+        (at(4, b"ftyp") && (at(8, b"M4A ") || at(8, b"M4B ") || at(8, b"M4P ")))
+}
+
+/// Asks ffmpeg whether the file has a supported audio stream.  Files with a real video stream
+/// are left out, but embedded cover art is fine.
+///
+/// # Arguments
+///
+/// * `path` - The file to check
+fn has_audio_stream(path: &Path) -> bool {
+    ffmpeg::init().unwrap();
+    let context = match ffmpeg::format::input(&path) {
+        Ok(context) => context,
+        Err(_) => return false,
+    };
+
+    let audio = context
+        .streams()
+        .best(ffmpeg::media::Type::Audio)
+        .map_or(false, |stream| {
+            ::ALL_CODECS.contains_key(&UniCase(stream.codec().id().name()))
+        });
+    let video = context.streams().any(|stream| {
+        stream.codec().medium() == ffmpeg::media::Type::Video &&
+            !is_cover_art(stream.codec().id())
+    });
+
+    audio && !video
+}
+
+/// Checks whether a video codec is one used for still images, as in embedded album art
+///
+/// # Arguments
+///
+/// * `id` - The codec of a video stream
+fn is_cover_art(id: codec::id::Id) -> bool {
+    match id {
+        codec::id::Id::MJPEG | codec::id::Id::PNG | codec::id::Id::BMP | codec::id::Id::GIF => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Detection, has_audio_signature, is_audio};
+    use std::path::Path;
+
+    #[test]
+    fn test_signatures() {
+        assert!(has_audio_signature(b"fLaC\0\0\0\x22"));
+        assert!(has_audio_signature(b"RIFF\x24\x08\0\0WAVEfmt "));
+        assert!(has_audio_signature(b"\0\0\0\x20ftypM4A \0\0\0\0"));
+        assert!(!has_audio_signature(b"RIFF\x24\x08\0\0AVI LIST"));
+        assert!(!has_audio_signature(b"\0\0\0\x20ftypisom"));
+        assert!(!has_audio_signature(b"Not music"));
+        assert!(!has_audio_signature(b""));
+    }
+
+    #[test]
+    fn test_thorough_detection() {
+        let music = Path::new("test-files/folder1/How Doth The Little Crocodile.mp3");
+        let text = Path::new("test-files/folder1/File about Crocodiles.txt");
+        assert!(is_audio(music, Detection::Thorough));
+        assert!(!is_audio(text, Detection::Thorough));
+        assert!(!is_audio(Path::new("test-files/folder1"), Detection::Thorough));
+    }
+
+    #[test]
+    fn test_fast_detection() {
+        let music = Path::new("test-files/folder1/How Doth The Little Crocodile.mp3");
+        assert!(is_audio(music, Detection::Fast));
+        assert!(!is_audio(Path::new("test-files/notmusic.txt"), Detection::Fast));
+    }
+}
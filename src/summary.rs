@@ -0,0 +1,206 @@
+use musicfile::Action;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use syncer::Report;
+
+#[derive(Debug, Default, PartialEq)]
+/// Totals for a run, for showing at the end of it
+pub struct Summary {
+    /// The number of files copied
+    pub copied: usize,
+    /// The number of files converted
+    pub transcoded: usize,
+    /// The number of synced copies moved to follow their sources
+    pub moved: usize,
+    /// The number of files left alone
+    pub skipped: usize,
+    /// The number of files that failed
+    pub failed: usize,
+    /// The number of files removed from the destination
+    pub deleted: usize,
+    /// The size of the sources that were copied or converted
+    pub bytes_read: u64,
+    /// The size of the files written to the destination
+    pub bytes_written: u64,
+    /// How long the run took
+    pub elapsed: Duration,
+    /// The files that failed, with the error and everything that caused it, in path order
+    pub failures: Vec<(PathBuf, Vec<String>)>,
+}
+
+impl Summary {
+    /// Totals up what happened in a sync
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - The outcome of `Syncer::execute`
+    pub fn new(report: &Report) -> Summary {
+        let mut summary = Summary {
+            deleted: report.removed.len(),
+            elapsed: report.elapsed,
+            ..Summary::default()
+        };
+        for (source, result) in &report.results {
+            match *result {
+                Ok(Action::Copy(ref dest)) => {
+                    summary.copied += 1;
+                    summary.bytes_read += size(source);
+                    summary.bytes_written += size(dest);
+                }
+                Ok(Action::Transcode(ref dest)) => {
+                    summary.transcoded += 1;
+                    summary.bytes_read += size(source);
+                    summary.bytes_written += size(dest);
+                }
+                Ok(Action::Move(..)) => summary.moved += 1,
+                Ok(Action::Keep(_)) |
+                Ok(Action::Exclude(_)) => summary.skipped += 1,
+                Err(ref e) => {
+                    summary.failed += 1;
+                    summary.failures.push((
+                        source.clone(),
+                        e.iter().map(|e| e.to_string()).collect(),
+                    ));
+                }
+            }
+        }
+        summary.failures.sort();
+        summary
+    }
+
+    /// The size of what was written compared to what was read, if anything was read
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.bytes_read == 0 {
+            None
+        } else {
+            Some(self.bytes_written as f64 / self.bytes_read as f64)
+        }
+    }
+
+    /// The bytes read per second, if the run took any time
+    pub fn throughput(&self) -> Option<f64> {
+        let seconds = self.elapsed.as_secs() as f64 +
+            f64::from(self.elapsed.subsec_nanos()) / 1e9;
+        if seconds > 0.0 {
+            Some(self.bytes_read as f64 / seconds)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Copied:      {}", self.copied)?;
+        writeln!(f, "Transcoded:  {}", self.transcoded)?;
+        writeln!(f, "Moved:       {}", self.moved)?;
+        writeln!(f, "Skipped:     {}", self.skipped)?;
+        writeln!(f, "Failed:      {}", self.failed)?;
+        if self.deleted > 0 {
+            writeln!(f, "Deleted:     {}", self.deleted)?;
+        }
+        writeln!(f, "Read:        {}", human_bytes(self.bytes_read as f64))?;
+        writeln!(f, "Written:     {}", human_bytes(self.bytes_written as f64))?;
+        if let Some(ratio) = self.compression_ratio() {
+            writeln!(f, "Compression: {:.1}%", ratio * 100.0)?;
+        }
+        let seconds = self.elapsed.as_secs();
+        write!(f, "Time:        {}:{:02}", seconds / 60, seconds % 60)?;
+        if let Some(throughput) = self.throughput() {
+            write!(f, " ({}/s)", human_bytes(throughput))?;
+        }
+
+        for &(ref path, ref errors) in &self.failures {
+            write!(f, "\nerror processing {}", path.display())?;
+            for (i, error) in errors.iter().enumerate() {
+                if i == 0 {
+                    write!(f, ": {}", error)?;
+                } else {
+                    write!(f, "\n\tcaused by: {}", error)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The size of a file in bytes, or 0 if it can't be read
+fn size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Formats a number of bytes with a binary unit, i.e. `1.5 MiB`
+///
+/// # Arguments
+///
+/// * `bytes` - The number of bytes
+pub fn human_bytes(bytes: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", value, units[unit])
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use musicfile::Action;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use syncer::Report;
+    use super::{Summary, human_bytes};
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(512.0), "512 B");
+        assert_eq!(human_bytes(1536.0), "1.5 KiB");
+        assert_eq!(human_bytes(3.0 * 1024.0 * 1024.0 * 1024.0), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut report = Report::default();
+        let source = PathBuf::from("test-files/folder1/How Doth The Little Crocodile.mp3");
+        report.results.insert(
+            source.clone(),
+            Ok(Action::Copy(source.clone())),
+        );
+        report.results.insert(
+            PathBuf::from("test-files/a.mp3"),
+            Ok(Action::Keep(PathBuf::from("dest/a.mp3"))),
+        );
+        report.results.insert(
+            PathBuf::from("test-files/b.mp3"),
+            Err("Failed to get codec".into()),
+        );
+        report.elapsed = Duration::from_secs(61);
+        report.removed.push(PathBuf::from("dest/c.mp3"));
+
+        let summary = Summary::new(&report);
+        assert_eq!(summary.copied, 1);
+        assert_eq!(summary.deleted, 1);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.failed, 1);
+        assert!(summary.bytes_read > 0);
+        assert_eq!(summary.bytes_read, summary.bytes_written);
+        assert_eq!(
+            summary.failures,
+            vec![
+                (
+                    PathBuf::from("test-files/b.mp3"),
+                    vec!["Failed to get codec".to_owned()],
+                ),
+            ]
+        );
+        assert!(summary.to_string().contains("Time:        1:01"));
+    }
+}
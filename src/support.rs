@@ -0,0 +1,136 @@
+extern crate ffmpeg;
+
+pub use errors::*;
+
+use codec::Codec;
+use ffmpeg::ffi;
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+#[derive(Debug, PartialEq)]
+/// What the linked ffmpeg can do with a codec
+pub struct Support {
+    /// Whether files in this codec can be read
+    pub decoder: bool,
+    /// Whether files can be converted to this codec
+    pub encoder: bool,
+    /// The muxer chosen for the codec's extension, and whether it can hold the codec
+    pub muxer: Option<(String, bool)>,
+}
+
+impl Support {
+    /// Asks ffmpeg about a codec from the codec table
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - The codec to check
+    pub fn check(codec: &Codec) -> Support {
+        ffmpeg::init().unwrap();
+        let id = codec_id(codec.name);
+        Support {
+            decoder: id.map_or(false, |id| ffmpeg::decoder::find(id).is_some()),
+            encoder: id.map_or(false, |id| ffmpeg::encoder::find(id).is_some()),
+            muxer: muxer(codec.extension, id),
+        }
+    }
+}
+
+/// Makes sure that files can be converted to the target format, so an unusable config is
+/// caught before any file is processed
+///
+/// # Arguments
+///
+/// * `target_format` - The `target_format` from the config
+pub fn check_target(target_format: &str) -> Result<()> {
+    let codec = ::find_codec(target_format).ok_or_else(|| {
+        format!("{} is not a supported codec (see `casm codecs`)", target_format)
+    })?;
+
+    let support = Support::check(codec);
+    if !support.encoder {
+        bail!("This build of ffmpeg has no encoder for {}", codec.name);
+    }
+    match support.muxer {
+        Some((_, true)) => Ok(()),
+        Some((muxer, false)) => {
+            bail!(
+                "The {} muxer for .{} files cannot hold {}",
+                muxer,
+                codec.extension,
+                codec.name
+            )
+        }
+        None => bail!("This build of ffmpeg cannot write .{} files", codec.extension),
+    }
+}
+
+/// Looks up the ffmpeg codec id for a name in the codec table.  The table uses the names of
+/// `ffmpeg::codec::id::Id`, which are the upper-case codec descriptor names.
+///
+/// # Arguments
+///
+/// * `name` - The name of the codec, i.e. `OPUS`
+fn codec_id(name: &str) -> Option<ffmpeg::codec::Id> {
+    let name = match CString::new(name.to_lowercase()) {
+        Ok(name) => name,
+        Err(_) => return None,
+    };
+    unsafe {
+        let descriptor = ffi::avcodec_descriptor_get_by_name(name.as_ptr());
+        if descriptor.is_null() {
+            None
+        } else {
+            Some((*descriptor).id.into())
+        }
+    }
+}
+
+/// Finds the muxer that ffmpeg picks for an extension, and whether it can hold the codec
+///
+/// # Arguments
+///
+/// * `extension` - The extension of output files, without a dot
+/// * `id` - The codec that would be written, if ffmpeg knows it
+fn muxer(extension: &str, id: Option<ffmpeg::codec::Id>) -> Option<(String, bool)> {
+    let filename = match CString::new(format!("output.{}", extension.trim_left_matches('.'))) {
+        Ok(filename) => filename,
+        Err(_) => return None,
+    };
+    unsafe {
+        let format = ffi::av_guess_format(ptr::null(), filename.as_ptr(), ptr::null());
+        if format.is_null() {
+            return None;
+        }
+        let name = CStr::from_ptr((*format).name).to_string_lossy().into_owned();
+        let holds = id.map_or(false, |id| {
+            ffi::avformat_query_codec(format, id.into(), ffi::FF_COMPLIANCE_NORMAL) == 1
+        });
+        Some((name, holds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg;
+    use super::{Support, check_target, codec_id};
+    use unicase::UniCase;
+
+    #[test]
+    fn test_codec_id() {
+        ffmpeg::init().unwrap();
+        assert_eq!(codec_id("MP3"), Some(ffmpeg::codec::Id::MP3));
+        assert_eq!(codec_id("PCM_S16LE"), Some(ffmpeg::codec::Id::PCM_S16LE));
+        assert_eq!(codec_id("NOT_A_CODEC"), None);
+    }
+
+    #[test]
+    fn test_mp3_decoder() {
+        let support = Support::check(::ALL_CODECS.get(&UniCase("MP3")).unwrap());
+        assert!(support.decoder);
+    }
+
+    #[test]
+    fn test_unknown_target() {
+        assert!(check_target("NOT_A_CODEC").is_err());
+    }
+}
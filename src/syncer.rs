@@ -0,0 +1,774 @@
+pub use errors::*;
+
+use autoplaylist;
+use budget::{self, Budget};
+use cache::Cache;
+use cancel::Cancel;
+use config::{Config, SpaceCheck};
+use exclude::Exclude;
+use fs2;
+use manifest::Manifest;
+use musicfile::{self, Action, Musicfile, Probe};
+use observer::Observer;
+use scoped_threadpool::Pool;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use summary;
+
+/// The number of files that are probed or converted at once
+const THREADS: u32 = 4;
+
+/// How often, in seconds, the manifest is saved while syncing, so that little is lost if the
+/// process is killed
+const MANIFEST_SAVE_INTERVAL: u64 = 10;
+
+/// How far, as a fraction of `convert_profile.bit_rate`, the bit rate of a synced copy that was
+/// converted before fingerprints were recorded may be before it is converted again
+const BIT_RATE_TOLERANCE: f64 = 0.25;
+
+#[derive(Debug)]
+/// What planning decided to do with a music file
+pub struct Planned {
+    /// The source music file
+    pub file: Musicfile,
+    /// What syncing will do with it, or why that couldn't be decided
+    pub action: Result<Action>,
+    /// What was read from the file, if it could be read
+    pub probe: Option<Probe>,
+}
+
+impl Planned {
+    /// Estimates how much room the file takes in the destination once it is synced.  A
+    /// conversion is estimated from the bit rate and the length of the track, and anything
+    /// else from the size of the file that ends up there.
+    ///
+    /// # Arguments
+    ///
+    /// * `bit_rate` - The bit rate of conversions in KB/s
+    pub fn estimated_size(&self, bit_rate: usize) -> u64 {
+        let size = |path: &Path| fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        match self.action {
+            Ok(Action::Transcode(_)) => {
+                match self.probe.as_ref().and_then(|p| p.duration) {
+                    Some(duration) => {
+                        let seconds = duration.as_secs() as f64 +
+                            f64::from(duration.subsec_nanos()) / 1e9;
+                        (bit_rate as f64 * 1024.0 / 8.0 * seconds) as u64
+                    }
+                    None => size(&self.file.filename),
+                }
+            }
+            Ok(Action::Copy(_)) => size(&self.file.filename),
+            Ok(Action::Keep(ref dest)) |
+            Ok(Action::Move(ref dest, _)) => size(dest),
+            Ok(Action::Exclude(_)) | Err(_) => 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// The outcome of carrying out a plan
+pub struct Report {
+    /// What happened to each music file, by source path
+    pub results: HashMap<PathBuf, Result<Action>>,
+    /// How long carrying out the plan took
+    pub elapsed: Duration,
+    /// Whether the sync was cancelled, leaving some files unsynced
+    pub cancelled: bool,
+    /// Whether the destination filled up, so that the sync stopped early
+    pub out_of_space: bool,
+    /// The files that `sync` removed from the destination to make room, before executing
+    pub removed: Vec<PathBuf>,
+}
+
+/// Syncs music according to a config.  `sync` does everything, or the steps can be run one at
+/// a time with `scan`, `plan`, `remove_left_out`, `check_space`, `execute` and
+/// `write_playlists`.  Progress is reported to an
+/// `Observer`, and a sync can be stopped early through `cancel_handle`.
+pub struct Syncer {
+    config: Config,
+    exclude: Exclude,
+    budget: Option<Budget>,
+    cache: Option<Cache>,
+    cancel: Cancel,
+}
+
+impl Syncer {
+    /// Creates a syncer, failing with every problem in the config if it isn't usable
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The user-specified config
+    pub fn new(config: Config) -> Result<Syncer> {
+        let problems = config.validate();
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(|p| format!("  {}", p)).collect();
+            bail!("Invalid config:\n{}", problems.join("\n"));
+        }
+
+        let exclude = Exclude::new(
+            &config.source_folder,
+            &config.exclude,
+            &config.exclude_globs,
+            &config.exclude_tags,
+        )?;
+        let budget = Budget::new(&config)?;
+        let cache = config.cache_folder.as_ref().map(
+            |folder| Cache::new(Path::new(folder)),
+        );
+
+        Ok(Syncer {
+            config: config,
+            exclude: exclude,
+            budget: budget,
+            cache: cache,
+            cancel: Cancel::new(),
+        })
+    }
+
+    /// The config that the syncer was created with
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The compiled exclusion rules from the config
+    pub fn exclude(&self) -> &Exclude {
+        &self.exclude
+    }
+
+    /// A handle that stops planning and syncing when it is raised, i.e. from a signal handler.
+    /// Files that have finished are kept, and a later sync picks up where this one stopped.
+    pub fn cancel_handle(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    /// Finds the music files in the configured files, folders, globs and playlists, in path
+    /// order
+    pub fn scan(&self) -> Vec<Musicfile> {
+        let mut files: Vec<Musicfile> = ::scan_files(
+            &self.config.source_folder,
+            self.patterns(),
+            &self.exclude,
+            self.config.detection,
+        ).into_iter()
+            .collect();
+        files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        files
+    }
+
+    /// Finds the music files that are at or under any of the given paths, i.e. the ones that
+    /// have just changed, in path order.  The same files, folders, globs, playlists and
+    /// exclusions apply as for `scan`, but only the changed paths are walked.
+    ///
+    /// # Arguments
+    ///
+    /// * `changed` - The files and folders that have changed
+    pub fn scan_changed(&self, changed: &[PathBuf]) -> Vec<Musicfile> {
+        let mut files: Vec<Musicfile> = ::scan_changed_files(
+            &self.config.source_folder,
+            self.patterns(),
+            changed,
+            &self.exclude,
+            self.config.detection,
+        ).into_iter()
+            .collect();
+        files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        files
+    }
+
+    /// Removes the synced copies of source files that are gone, as recorded in the manifest.
+    /// Returns the files removed from the destination.
+    ///
+    /// # Arguments
+    ///
+    /// * `removed` - The files and folders that have been removed from the source
+    pub fn remove_deleted(&self, removed: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let source_folder = Path::new(&self.config.source_folder);
+        let dest_folder = Path::new(&self.config.dest_folder);
+        let mut manifest = Manifest::load(dest_folder)?;
+
+        let gone: Vec<PathBuf> = manifest
+            .entries
+            .keys()
+            .filter(|source| {
+                let source = source_folder.join(source);
+                !source.exists() && removed.iter().any(|path| source.starts_with(path))
+            })
+            .cloned()
+            .collect();
+
+        let mut deleted = Vec::new();
+        for source in gone {
+            let entry = manifest.entries.remove(&source).unwrap();
+            let dest = dest_folder.join(&entry.dest);
+            if dest.exists() {
+                fs::remove_file(&dest).chain_err(|| {
+                    format!("Unable to remove {}", dest.display())
+                })?;
+                musicfile::remove_empty_parents(&dest, dest_folder);
+                deleted.push(dest);
+            }
+        }
+        manifest.save()?;
+        Ok(deleted)
+    }
+
+    /// Removes the synced copies of files that were left out to fit in `max_size`, or that the
+    /// current rotation no longer chooses, as recorded in the manifest, to make room for the
+    /// ones that are synced.  Returns the files removed from the destination.
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The plan from `plan`
+    pub fn remove_left_out(&self, plan: &[Planned]) -> Result<Vec<PathBuf>> {
+        if self.budget.is_none() {
+            return Ok(Vec::new());
+        }
+        let source_folder = Path::new(&self.config.source_folder);
+        let dest_folder = Path::new(&self.config.dest_folder);
+        let mut manifest = Manifest::load(dest_folder)?;
+
+        let mut deleted = Vec::new();
+        for planned in plan {
+            match planned.action {
+                Ok(Action::Exclude(ref reason))
+                    if reason == budget::LEFT_OUT || reason == budget::ROTATED_OUT => {}
+                _ => continue,
+            }
+            let filename = &planned.file.filename;
+            let source = filename.strip_prefix(source_folder).unwrap_or(filename);
+            if let Some(entry) = manifest.entries.remove(source) {
+                let dest = dest_folder.join(&entry.dest);
+                if dest.exists() {
+                    fs::remove_file(&dest).chain_err(|| {
+                        format!("Unable to remove {}", dest.display())
+                    })?;
+                    musicfile::remove_empty_parents(&dest, dest_folder);
+                    deleted.push(dest);
+                }
+            }
+        }
+        manifest.save()?;
+        Ok(deleted)
+    }
+
+    /// Probes each music file and decides what syncing would do with it, without touching the
+    /// destination.  The plan is in path order.  If it is cancelled before every file is
+    /// planned, it fails with `ErrorKind::Cancelled` rather than giving a partial plan.
+    ///
+    /// # Arguments
+    ///
+    /// * `files` - The music files to plan for, usually from `scan`
+    /// * `observer` - Told what is decided for each file
+    pub fn plan(&self, files: Vec<Musicfile>, observer: &Observer) -> Result<Vec<Planned>> {
+        let mut pool = Pool::new(THREADS);
+        let planned = Mutex::new(Vec::with_capacity(files.len()));
+
+        pool.scoped(|scope| for file in files {
+            let planned = &planned;
+            scope.execute(move || {
+                if self.cancel.is_cancelled() {
+                    return;
+                }
+                let (probe, action) = match file.probe().chain_err(|| "Failed to get codec") {
+                    Ok(probe) => {
+                        let action = file.plan_probed(
+                            &probe,
+                            &self.config.source_folder,
+                            &self.config.dest_folder,
+                            &self.config.convert_profile,
+                            &self.exclude,
+                        );
+                        (Some(probe), action)
+                    }
+                    Err(e) => (None, Err(e)),
+                };
+                planned.lock().unwrap().push(Planned {
+                    file: file,
+                    action: action,
+                    probe: probe,
+                });
+            });
+        });
+
+        if self.cancel.is_cancelled() {
+            bail!(ErrorKind::Cancelled);
+        }
+        let mut planned = planned.into_inner().unwrap();
+        planned.sort_by(|a, b| a.file.filename.cmp(&b.file.filename));
+        match Manifest::load(Path::new(&self.config.dest_folder)) {
+            Ok(manifest) => {
+                self.find_moves(&mut planned, &manifest);
+                self.find_stale(&mut planned, &manifest);
+            }
+            Err(e) => warn!("{}; not looking for moved or reconfigured files", e),
+        }
+        if let Some(ref budget) = self.budget {
+            let left_out = budget.fit(&mut planned, self.config.convert_profile.bit_rate);
+            if left_out.files > 0 && self.config.rotation.is_some() {
+                info!(
+                    "{} files ({}) are not in this rotation",
+                    left_out.files,
+                    summary::human_bytes(left_out.bytes as f64)
+                );
+            } else if left_out.files > 0 {
+                warn!(
+                    "Left out {} files ({}) to fit in max_size",
+                    left_out.files,
+                    summary::human_bytes(left_out.bytes as f64)
+                );
+            }
+        }
+        for planned in &planned {
+            if let Ok(ref action) = planned.action {
+                observer.planned(&planned.file, action, planned.probe.as_ref());
+            }
+        }
+        Ok(planned)
+    }
+
+    /// Turns copies and conversions of files that have been synced before under another name
+    /// into moves of their synced copies, so that reorganizing the source doesn't mean syncing
+    /// it again.  Only synced copies in the format that would be written are moved.
+    ///
+    /// # Arguments
+    ///
+    /// * `planned` - The plan, which is changed in place
+    /// * `manifest` - The files that have been synced before
+    fn find_moves(&self, planned: &mut [Planned], manifest: &Manifest) {
+        let source_folder = Path::new(&self.config.source_folder);
+        let dest_folder = Path::new(&self.config.dest_folder);
+
+        let mut claimed = HashSet::new();
+        for planned in planned.iter_mut() {
+            let moved = match planned.action {
+                Ok(Action::Copy(ref dest)) |
+                Ok(Action::Transcode(ref dest)) => {
+                    manifest
+                        .find_moved(source_folder, dest_folder, &planned.file.filename)
+                        .map(|entry| dest_folder.join(&entry.dest))
+                        .and_then(|from| if from.extension() == dest.extension() &&
+                            !claimed.contains(&from)
+                        {
+                            Some((from, dest.clone()))
+                        } else {
+                            None
+                        })
+                }
+                _ => None,
+            };
+            if let Some((from, to)) = moved {
+                claimed.insert(from.clone());
+                planned.action = Ok(Action::Move(from, to));
+            }
+        }
+    }
+
+    /// Turns files that would be kept, but were converted with other `convert_profile` settings,
+    /// into conversions, so that changing i.e. the bit rate applies to files synced before.  If
+    /// `reconvert_on_change` is off they are kept, and only counted in a warning.
+    ///
+    /// # Arguments
+    ///
+    /// * `planned` - The plan, which is changed in place
+    /// * `manifest` - The files that have been synced before
+    fn find_stale(&self, planned: &mut [Planned], manifest: &Manifest) {
+        let source_folder = Path::new(&self.config.source_folder);
+        let dest_folder = Path::new(&self.config.dest_folder);
+        let profile = &self.config.convert_profile;
+        let fingerprint = profile.fingerprint();
+
+        let mut stale = 0;
+        for planned in planned.iter_mut() {
+            let dest = match planned.action {
+                Ok(Action::Keep(ref dest)) => dest.clone(),
+                _ => continue,
+            };
+            // A source in an acceptable format is copied, so its synced copy has no settings
+            let copied = planned
+                .probe
+                .as_ref()
+                .and_then(|probe| ::find_codec(probe.codec.name()))
+                .map_or(false, |codec| codec.is_acceptable(&profile.acceptable_formats));
+            let is_stale = manifest
+                .is_stale(
+                    source_folder,
+                    dest_folder,
+                    &planned.file.filename,
+                    &dest,
+                    &fingerprint,
+                )
+                .unwrap_or_else(|| !copied && self.converted_otherwise(&dest));
+            if is_stale {
+                stale += 1;
+                if profile.reconvert_on_change {
+                    planned.action = Ok(Action::Transcode(dest));
+                }
+            }
+        }
+        if stale > 0 && !profile.reconvert_on_change {
+            warn!(
+                "{} files were converted with other convert_profile settings, and are kept \
+                 because reconvert_on_change is off",
+                stale
+            );
+        }
+    }
+
+    /// Whether a synced conversion that was recorded without a fingerprint is in another codec
+    /// than `convert_profile` asks for, or is lossy and far from its bit rate.  A copy that
+    /// can't be read is left alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - The synced copy
+    fn converted_otherwise(&self, dest: &Path) -> bool {
+        let profile = &self.config.convert_profile;
+        let target = match ::find_codec(&profile.target_format) {
+            Some(codec) => codec,
+            None => return false,
+        };
+        let probe = match (Musicfile { filename: dest.to_path_buf() }).probe() {
+            Ok(probe) => probe,
+            Err(_) => return false,
+        };
+        if ::find_codec(probe.codec.name()).map_or(true, |codec| codec.name != target.name) {
+            return true;
+        }
+        let wanted = (profile.bit_rate * 1024) as f64;
+        !target.lossless &&
+            probe.bit_rate.map_or(false, |actual| {
+                (actual as f64 - wanted).abs() > wanted * BIT_RATE_TOLERANCE
+            })
+    }
+
+    /// Turns files that would be kept into copies or conversions if they have been modified
+    /// since their synced copy was written, i.e. retagged while `casm watch` is running.  `plan`
+    /// only checks that a synced copy is there.
+    ///
+    /// # Arguments
+    ///
+    /// * `planned` - The plan, which is changed in place
+    pub fn replace_outdated(&self, planned: &mut [Planned]) {
+        let acceptable_formats = &self.config.convert_profile.acceptable_formats;
+        for planned in planned.iter_mut() {
+            let dest = match planned.action {
+                Ok(Action::Keep(ref dest)) if planned.file.is_outdated(dest) => dest.clone(),
+                _ => continue,
+            };
+            let acceptable = planned
+                .probe
+                .as_ref()
+                .and_then(|probe| ::find_codec(probe.codec.name()))
+                .map_or(false, |codec| codec.is_acceptable(acceptable_formats));
+            planned.action = Ok(if acceptable {
+                Action::Copy(dest)
+            } else {
+                Action::Transcode(dest)
+            });
+        }
+    }
+
+    /// Checks that the files a plan copies and converts fit in the free space of the filesystem
+    /// that `dest_folder` is on, going by `Planned::estimated_size`.  Files that replace an
+    /// existing file only need the difference.  Depending on `space_check`, a plan that doesn't
+    /// fit fails, is only warned about, or isn't checked at all.
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The plan from `plan`
+    pub fn check_space(&self, plan: &[Planned]) -> Result<()> {
+        if self.config.space_check == SpaceCheck::Off {
+            return Ok(());
+        }
+        let bit_rate = self.config.convert_profile.bit_rate;
+        let needed: u64 = plan.iter()
+            .filter_map(|planned| match planned.action {
+                Ok(Action::Copy(ref dest)) |
+                Ok(Action::Transcode(ref dest)) => {
+                    let existing = fs::metadata(dest).map(|m| m.len()).unwrap_or(0);
+                    Some(planned.estimated_size(bit_rate).saturating_sub(existing))
+                }
+                _ => None,
+            })
+            .sum();
+        if needed == 0 {
+            return Ok(());
+        }
+
+        // The destination may not have been created yet
+        let mut folder = Path::new(&self.config.dest_folder);
+        while !folder.exists() {
+            match folder.parent() {
+                Some(parent) => folder = parent,
+                None => break,
+            }
+        }
+        let available = fs2::available_space(folder).chain_err(|| {
+            format!("Unable to get the free space of {}", folder.display())
+        })?;
+        if needed > available {
+            let message = format!(
+                "The files to sync need about {}, but only {} is free in {}",
+                summary::human_bytes(needed as f64),
+                summary::human_bytes(available as f64),
+                self.config.dest_folder
+            );
+            if self.config.space_check == SpaceCheck::Warn {
+                warn!("{}", message);
+            } else {
+                bail!("{}; set max_size to sync less", message);
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies and converts files as planned.  This is done in a multithreaded manner.
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The plan from `plan`
+    /// * `observer` - Told as each file starts, progresses, finishes, is skipped or fails
+    pub fn execute(&self, plan: Vec<Planned>, observer: &Observer) -> Report {
+        let began = Instant::now();
+        let mut pool = Pool::new(THREADS);
+        let results = Mutex::new(HashMap::new());
+        let full = AtomicBool::new(false);
+        let source_folder = Path::new(&self.config.source_folder);
+        let dest_folder = Path::new(&self.config.dest_folder);
+        // Kept where `Cancel::save_progress` can find it, in case the process exits early
+        let manifest = self.cancel.progress();
+        *manifest.lock().unwrap() = Some(Manifest::load(dest_folder).unwrap_or_else(|e| {
+            warn!("{}; starting a new manifest", e);
+            Manifest::new(dest_folder)
+        }));
+        let saved = Mutex::new(Instant::now());
+
+        observer.begin(plan.len());
+
+        pool.scoped(|scope| for planned in plan {
+            let results = &results;
+            let full = &full;
+            let saved = &saved;
+            scope.execute(move || {
+                if self.cancel.is_cancelled() || full.load(Ordering::SeqCst) {
+                    return;
+                }
+                let duration = planned.probe.as_ref().and_then(|probe| probe.duration);
+                let file = planned.file;
+                let pending = planned.action.as_ref().ok().cloned();
+                let result = planned.action.and_then(|action| {
+                    match action {
+                        Action::Keep(_) |
+                        Action::Exclude(_) => observer.skipped(&file, &action),
+                        Action::Copy(ref dest) |
+                        Action::Transcode(ref dest) |
+                        Action::Move(_, ref dest) => {
+                            observer.started(&file, &action);
+                            self.write(&file, &action, duration, observer)?;
+                            if let Some(ref mut manifest) = *manifest.lock().unwrap() {
+                                let fingerprint = match action {
+                                    Action::Transcode(_) => {
+                                        Some(self.config.convert_profile.fingerprint())
+                                    }
+                                    Action::Move(ref from, _) => {
+                                        let moved = manifest.forget_dest(dest_folder, from);
+                                        musicfile::remove_empty_parents(from, dest_folder);
+                                        moved.and_then(|entry| entry.fingerprint)
+                                    }
+                                    _ => None,
+                                };
+                                if let Err(e) = manifest.record(
+                                    source_folder,
+                                    dest_folder,
+                                    &file.filename,
+                                    dest,
+                                    fingerprint,
+                                )
+                                {
+                                    warn!("{}", e);
+                                }
+                                let mut saved = saved.lock().unwrap();
+                                if saved.elapsed() >= Duration::from_secs(MANIFEST_SAVE_INTERVAL) {
+                                    if let Err(e) = manifest.save() {
+                                        warn!("{}", e);
+                                    }
+                                    *saved = Instant::now();
+                                }
+                            }
+                            observer.finished(&file, &action);
+                        }
+                    }
+                    Ok(action)
+                });
+                // Files in progress when the destination filled up fail the same way, but only
+                // the first is worth reporting as failed.  The rest, like files abandoned by a
+                // cancel, are told to the observer as skipped and left for the next sync.
+                let abandoned = match result {
+                    Err(Error(ErrorKind::Cancelled, _)) => true,
+                    Err(Error(ErrorKind::NoSpace, _)) => full.swap(true, Ordering::SeqCst),
+                    _ => false,
+                };
+                if abandoned {
+                    if let Some(ref action) = pending {
+                        observer.skipped(&file, action);
+                    }
+                    return;
+                }
+                if let Err(ref e) = result {
+                    match *e.kind() {
+                        ErrorKind::NoSpace => {
+                            error!(
+                                "{}: {} is full; not starting any more files",
+                                file.filename.display(),
+                                dest_folder.display()
+                            )
+                        }
+                        _ => {
+                            let causes: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+                            error!("{}: {}", file.filename.display(), causes.join(": "));
+                        }
+                    }
+                    observer.failed(&file, e);
+                }
+                results.lock().unwrap().insert(file.filename, result);
+            });
+        });
+        observer.end();
+
+        // Saved even when cancelled, so that the next sync knows what this one finished
+        if let Some(manifest) = manifest.lock().unwrap().take() {
+            if let Err(e) = manifest.save() {
+                error!("{}", e);
+            }
+        }
+
+        Report {
+            results: results.into_inner().unwrap(),
+            elapsed: began.elapsed(),
+            cancelled: self.cancel.is_cancelled(),
+            out_of_space: full.into_inner(),
+            removed: Vec::new(),
+        }
+    }
+
+    /// Carries out a copy, conversion or move, then checks the written file if `verification`
+    /// is set.  A file that fails the check is removed and written again, up to `retries`
+    /// times.  A conversion that fails is removed from the cache too, so that it is converted
+    /// again and the new conversion is stored in its place.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The source music file
+    /// * `action` - What to do with it
+    /// * `duration` - The length of the music file, if it is known
+    /// * `observer` - Told how the copy or conversion is progressing
+    fn write(
+        &self,
+        file: &Musicfile,
+        action: &Action,
+        duration: Option<Duration>,
+        observer: &Observer,
+    ) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            file.execute(
+                action,
+                &self.config.convert_profile,
+                self.cache.as_ref(),
+                observer,
+                &self.cancel,
+            )?;
+
+            let verification = match self.config.verification {
+                Some(ref verification) => verification,
+                None => return Ok(()),
+            };
+            let (dest, converted) = match *action {
+                Action::Copy(ref dest) => (dest, false),
+                Action::Transcode(ref dest) => (dest, true),
+                _ => return Ok(()),
+            };
+            match file.verify(dest, converted, duration, verification.tolerance) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    let _ = fs::remove_file(dest);
+                    if let (true, Some(cache)) = (converted, self.cache.as_ref()) {
+                        let extension = dest.extension().and_then(|e| e.to_str()).unwrap_or("");
+                        if let Err(e) = Cache::key(&file.filename, &self.config.convert_profile)
+                            .and_then(|key| cache.remove(&key, extension))
+                        {
+                            warn!("{}", e);
+                        }
+                    }
+                    if retries >= verification.retries {
+                        return Err(e).chain_err(|| "Failed verification");
+                    }
+                    warn!("{}; writing it again", e);
+                    retries += 1;
+                }
+            }
+        }
+    }
+
+    /// Writes the exported and generated playlists that the config asks for
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - The outcome of `execute`
+    pub fn write_playlists(&self, report: &Report) -> Result<()> {
+        let source_folder = Path::new(&self.config.source_folder);
+        let dest_folder = Path::new(&self.config.dest_folder);
+
+        if let Some(ref export) = self.config.playlist_export {
+            for playlist in ::scan_playlists(&self.config.source_folder, self.patterns()) {
+                playlist
+                    .export(&report.results, source_folder, dest_folder, export)
+                    .chain_err(|| {
+                        format!("Unable to export playlist {}", playlist.path.display())
+                    })?;
+            }
+        }
+
+        if let Some(ref settings) = self.config.generate_playlists {
+            autoplaylist::generate(&report.results, dest_folder, settings)
+                .chain_err(|| "Unable to generate playlists")?;
+        }
+
+        Ok(())
+    }
+
+    /// Scans and plans, removes what no longer fits, checks the free space and executes, then
+    /// writes playlists
+    ///
+    /// # Arguments
+    ///
+    /// * `observer` - Told how syncing is progressing
+    pub fn sync(&self, observer: &Observer) -> Result<Report> {
+        let files = self.scan();
+        trace!("Files:\n{:#?}", files);
+        let plan = self.plan(files, observer)?;
+        let removed = self.remove_left_out(&plan)?;
+        for dest in &removed {
+            info!("Removed {}", dest.display());
+        }
+        self.check_space(&plan)?;
+        let mut report = self.execute(plan, observer);
+        report.removed = removed;
+        if !report.cancelled && !report.out_of_space {
+            self.write_playlists(&report)?;
+        }
+        Ok(report)
+    }
+
+    /// The files, folders, globs and playlists to scan
+    fn patterns(&self) -> Vec<String> {
+        let mut patterns = self.config.files.clone();
+        patterns.extend(self.config.playlists.clone().unwrap_or_default());
+        patterns
+    }
+}
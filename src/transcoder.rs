@@ -1,7 +1,12 @@
 extern crate ffmpeg;
 
+pub use errors::*;
+
+use libc;
+use std::cmp;
 use std::iter::FromIterator;
 use std::path::Path;
+use std::time::Duration;
 
 use ffmpeg::{format, codec, frame, media, filter};
 
@@ -9,7 +14,7 @@ fn filter(
     spec: &str,
     decoder: &codec::decoder::Audio,
     encoder: &codec::encoder::Audio,
-) -> Result<filter::Graph, ffmpeg::Error> {
+) -> ::std::result::Result<filter::Graph, ffmpeg::Error> {
     let mut filter = filter::Graph::new();
 
     let args = format!(
@@ -57,28 +62,35 @@ struct Transcoder {
     encoder: codec::encoder::Audio,
 }
 
+fn decoder(
+    ictx: &format::context::Input,
+) -> ::std::result::Result<(usize, codec::decoder::Audio), ffmpeg::Error> {
+    let input = try!(ictx.streams().best(media::Type::Audio).ok_or(
+        ffmpeg::Error::StreamNotFound,
+    ));
+    let mut decoder = try!(input.codec().decoder().audio());
+    try!(decoder.set_parameters(input.parameters()));
+    Ok((input.index(), decoder))
+}
+
 fn transcoder<P: AsRef<Path>>(
     ictx: &mut format::context::Input,
     octx: &mut format::context::Output,
     path: &P,
     filter_spec: &str,
     bit_rate: usize,
-) -> Result<Transcoder, ffmpeg::Error> {
-    let input = ictx.streams().best(media::Type::Audio).expect(
-        "could not find best audio stream",
-    );
-    let mut decoder = try!(input.codec().decoder().audio());
+) -> Result<Transcoder> {
+    let (stream, decoder) = try!(decoder(ictx));
     let codec = try!(
-        ffmpeg::encoder::find(octx.format().codec(path, media::Type::Audio))
-            .expect("failed to find encoder")
-            .audio()
+        try!(
+            ffmpeg::encoder::find(octx.format().codec(path, media::Type::Audio))
+                .ok_or(ffmpeg::Error::EncoderNotFound)
+        ).audio()
     );
     let global = octx.format().flags().contains(
         ffmpeg::format::flag::GLOBAL_HEADER,
     );
 
-    try!(decoder.set_parameters(input.parameters()));
-
     let mut output = try!(octx.add_stream(codec));
     let mut encoder = try!(output.codec().encoder().audio());
 
@@ -94,13 +106,11 @@ fn transcoder<P: AsRef<Path>>(
     encoder.set_rate(select_best_rate(decoder.rate() as i32, codec.rates()));
     encoder.set_channel_layout(channel_layout);
     encoder.set_channels(channel_layout.channels());
-    encoder.set_format(
-        codec
-            .formats()
-            .expect("unknown supported formats")
-            .next()
-            .unwrap(),
-    );
+    encoder.set_format(try!(
+        codec.formats().and_then(|mut formats| formats.next()).ok_or_else(|| {
+            format!("The {} encoder has no sample formats", codec.name())
+        })
+    ));
     encoder.set_bit_rate(bit_rate);
 
     encoder.set_time_base((1, decoder.rate() as i32));
@@ -112,22 +122,54 @@ fn transcoder<P: AsRef<Path>>(
     let filter = try!(filter(filter_spec, &decoder, &encoder));
 
     Ok(Transcoder {
-        stream: input.index(),
+        stream: stream,
         filter: filter,
         decoder: decoder,
         encoder: encoder,
     })
 }
 
-pub fn convert(input: &str, output: &str, filter: &str, bit_rate: usize) {
+/// Converts the best audio stream of a file to the format that ffmpeg picks for the output's
+/// extension, copying over the metadata
+///
+/// # Arguments
+///
+/// * `input` - The file to convert
+/// * `output` - Where to write the converted file
+/// * `filter` - An ffmpeg filter graph description to pass the audio through, i.e. `anull`
+/// * `bit_rate` - The target bit rate in bits per second
+/// * `progress` - Called with the position in bytes of each packet read from the input.  The
+/// conversion is abandoned if it returns `false`.
+pub fn convert(
+    input: &str,
+    output: &str,
+    filter: &str,
+    bit_rate: usize,
+    progress: &Fn(u64) -> bool,
+) -> Result<()> {
+    transcode(input, output, filter, bit_rate, progress).or_else(|e| match *e.kind() {
+        ErrorKind::Ffmpeg(ffmpeg::Error::Other { errno }) if errno == libc::ENOSPC => {
+            bail!(ErrorKind::NoSpace)
+        }
+        _ => Err(e).chain_err(|| format!("Could not convert {} to {}", input, output)),
+    })
+}
+
+fn transcode(
+    input: &str,
+    output: &str,
+    filter: &str,
+    bit_rate: usize,
+    progress: &Fn(u64) -> bool,
+) -> Result<()> {
     ffmpeg::init().unwrap();
 
-    let mut ictx = format::input(&input).unwrap();
-    let mut octx = format::output(&output).unwrap();
-    let mut transcoder = transcoder(&mut ictx, &mut octx, &output, filter, bit_rate).unwrap();
+    let mut ictx = try!(format::input(&input));
+    let mut octx = try!(format::output(&output));
+    let mut transcoder = try!(transcoder(&mut ictx, &mut octx, &output, filter, bit_rate));
 
     octx.set_metadata(ictx.metadata().to_owned());
-    octx.write_header().unwrap();
+    try!(octx.write_header());
 
     let in_time_base = transcoder.decoder.time_base();
     let out_time_base = octx.stream(0).unwrap().time_base();
@@ -136,6 +178,9 @@ pub fn convert(input: &str, output: &str, filter: &str, bit_rate: usize) {
     let mut encoded = ffmpeg::Packet::empty();
 
     for (stream, mut packet) in ictx.packets() {
+        if !progress(cmp::max(packet.position(), 0) as u64) {
+            return Err(ffmpeg::Error::Exit.into());
+        }
         if stream.index() == transcoder.stream {
             packet.rescale_ts(stream.time_base(), in_time_base);
 
@@ -143,13 +188,7 @@ pub fn convert(input: &str, output: &str, filter: &str, bit_rate: usize) {
                 let timestamp = decoded.timestamp();
                 decoded.set_pts(timestamp);
 
-                transcoder
-                    .filter
-                    .get("in")
-                    .unwrap()
-                    .source()
-                    .add(&decoded)
-                    .unwrap();
+                try!(transcoder.filter.get("in").unwrap().source().add(&decoded));
 
                 while let Ok(..) = transcoder.filter.get("out").unwrap().sink().frame(
                     &mut decoded,
@@ -158,20 +197,14 @@ pub fn convert(input: &str, output: &str, filter: &str, bit_rate: usize) {
                     if let Ok(true) = transcoder.encoder.encode(&decoded, &mut encoded) {
                         encoded.set_stream(0);
                         encoded.rescale_ts(in_time_base, out_time_base);
-                        encoded.write_interleaved(&mut octx).unwrap();
+                        try!(encoded.write_interleaved(&mut octx));
                     }
                 }
             }
         }
     }
 
-    transcoder
-        .filter
-        .get("in")
-        .unwrap()
-        .source()
-        .flush()
-        .unwrap();
+    try!(transcoder.filter.get("in").unwrap().source().flush());
 
     while let Ok(..) = transcoder.filter.get("out").unwrap().sink().frame(
         &mut decoded,
@@ -180,17 +213,56 @@ pub fn convert(input: &str, output: &str, filter: &str, bit_rate: usize) {
         if let Ok(true) = transcoder.encoder.encode(&decoded, &mut encoded) {
             encoded.set_stream(0);
             encoded.rescale_ts(in_time_base, out_time_base);
-            encoded.write_interleaved(&mut octx).unwrap();
+            try!(encoded.write_interleaved(&mut octx));
         }
     }
 
     if let Ok(true) = transcoder.encoder.flush(&mut encoded) {
         encoded.set_stream(0);
         encoded.rescale_ts(in_time_base, out_time_base);
-        encoded.write_interleaved(&mut octx).unwrap();
+        try!(encoded.write_interleaved(&mut octx));
     }
 
-    octx.write_trailer().unwrap();
+    try!(octx.write_trailer());
+    Ok(())
+}
+
+/// Decodes the best audio stream of a file all the way through, and returns how long it is.
+/// This fails if any of it can't be decoded, so it finds files that are corrupt or cut short.
+///
+/// # Arguments
+///
+/// * `input` - The file to decode
+pub fn decoded_duration(input: &str) -> Result<Duration> {
+    decode(input).chain_err(|| format!("Could not decode {}", input))
+}
+
+fn decode(input: &str) -> ::std::result::Result<Duration, ffmpeg::Error> {
+    ffmpeg::init().unwrap();
+
+    let mut ictx = try!(format::input(&input));
+    let (stream_index, mut decoder) = try!(decoder(&ictx));
+    let mut decoded = frame::Audio::empty();
+    let mut samples = 0u64;
+
+    for (stream, packet) in ictx.packets() {
+        if stream.index() == stream_index && try!(decoder.decode(&packet, &mut decoded)) {
+            samples += decoded.samples() as u64;
+        }
+    }
+    // Codecs with a delay hold back their last frames until they are flushed
+    while let Ok(true) = decoder.decode(&ffmpeg::Packet::empty(), &mut decoded) {
+        samples += decoded.samples() as u64;
+    }
+
+    let rate = u64::from(decoder.rate());
+    if rate == 0 {
+        return Err(ffmpeg::Error::InvalidData);
+    }
+    Ok(Duration::new(
+        samples / rate,
+        ((samples % rate) * 1_000_000_000 / rate) as u32,
+    ))
 }
 
 /// Get the closest sample rate without going under, unless there is nothing above
@@ -226,8 +298,10 @@ fn select_best_rate(source: i32, supported: Option<codec::audio::RateIter>) -> i
 
 #[cfg(test)]
 mod tests {
-    use super::select_best_rate;
+    use super::{convert, decoded_duration, select_best_rate};
     use ffmpeg::codec::audio::RateIter;
+    use std::cell::Cell;
+    use std::fs;
 
     #[test]
     fn test_best_rate_above() {
@@ -252,4 +326,23 @@ mod tests {
         let source: i32 = 41000;
         assert_eq!(select_best_rate(source, Some(supported)), 41000);
     }
+
+    #[test]
+    fn test_convert() {
+        let flac = "/tmp/casm-test-convert.flac";
+        let reads = Cell::new(0);
+        convert("test-files/Synth Synth Short.mp3", flac, "anull", 320 * 1024, &|_| {
+            reads.set(reads.get() + 1);
+            true
+        }).unwrap();
+        assert!(reads.get() > 0);
+        assert!(fs::metadata(flac).unwrap().len() > 0);
+    }
+
+    #[test]
+    fn test_decoded_duration() {
+        let duration = decoded_duration("test-files/Synth Synth Short.mp3").unwrap();
+        assert!(duration.as_secs() > 0);
+        assert!(decoded_duration("test-files/notmusic.txt").is_err());
+    }
 }